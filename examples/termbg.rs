@@ -11,6 +11,13 @@ fn main() {
                 println!("termbg {}", env!("CARGO_PKG_VERSION"));
                 return;
             }
+            "--diagnose" => {
+                print!(
+                    "{}",
+                    termbg::diagnose(std::time::Duration::from_millis(100))
+                );
+                return;
+            }
             "--debug" | "-d" => {
                 CombinedLogger::init(vec![TermLogger::new(
                     LevelFilter::Debug,
@@ -21,7 +28,10 @@ fn main() {
                 .unwrap();
             }
             _ => {
-                eprintln!("Usage: {} [--debug/-d] [--version/-V]", args[0]);
+                eprintln!(
+                    "Usage: {} [--debug/-d] [--diagnose] [--version/-V]",
+                    args[0]
+                );
                 std::process::exit(1);
             }
         }
@@ -31,15 +41,28 @@ fn main() {
 
     println!("Check terminal background color");
     let term = termbg::terminal();
-    let latency = termbg::latency(std::time::Duration::from_millis(1000));
+    let info = termbg::terminal_info(timeout);
+    let latency = termbg::latency_stats(10, std::time::Duration::from_millis(1000));
     let rgb = termbg::rgb(timeout);
     let theme = termbg::theme(timeout);
 
     println!("  Term : {:?}", term);
 
+    match info {
+        Ok(info) => {
+            println!("  Info : {:?}", info);
+        }
+        Err(e) => {
+            println!("  Info : detection failed {:?}", e);
+        }
+    }
+
     match latency {
         Ok(latency) => {
-            println!("  Latency: {:?}", latency);
+            println!(
+                "  Latency: min={:?}, median={:?}, p95={:?}, max={:?}",
+                latency.min, latency.median, latency.p95, latency.max
+            );
         }
         Err(e) => {
             println!("  Latency: detection failed {:?}", e);
@@ -0,0 +1,371 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use serde_json::{json, Map, Value};
+use simplelog::{ColorChoice, CombinedLogger, Config, LevelFilter, TermLogger, TerminalMode};
+use std::process;
+use std::time::{Duration, Instant};
+use termbg::{Detected, Error, Rgb, Theme};
+
+/// Exit code of success, and of dark theme by `theme`
+const EXIT_DARK: i32 = 0;
+/// Exit code of errors other than unsupported terminal and timeout
+const EXIT_ERROR: i32 = 1;
+/// Exit code when the terminal doesn't support detection
+const EXIT_UNSUPPORTED: i32 = 3;
+/// Exit code when the terminal doesn't answer within timeout
+const EXIT_TIMEOUT: i32 = 4;
+/// Exit code of light theme by `theme`
+const EXIT_LIGHT: i32 = 10;
+
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "Terminal background color detection",
+    after_help = "Exit status: 0 on success or dark theme, 1 on error, 2 on usage error,\n\
+                  3 on unsupported terminal, 4 on timeout, 10 on light theme"
+)]
+struct Opt {
+    /// Timeout of queries in milliseconds, or `auto` to derive it from the terminal latency
+    #[arg(short, long, default_value = "100", value_parser = parse_timeout)]
+    timeout: Timeout,
+
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t = Format::Plain)]
+    format: Format,
+
+    /// Show debug log
+    #[arg(short, long)]
+    debug: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Print the theme (`light` or `dark`)
+    Theme,
+    /// Print the background color
+    Bg,
+    /// Print the foreground color
+    Fg,
+    /// Print the palette colors
+    Palette {
+        /// Palette indices [default: 0 to 15]
+        indices: Vec<u8>,
+    },
+    /// Print the terminal latency in milliseconds
+    Latency {
+        /// Number of probes
+        #[arg(short, long, default_value = "10")]
+        samples: usize,
+    },
+    /// Print the detected terminal and its identification
+    Terminal,
+    /// Print every detection step
+    Diagnose,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// Values only
+    Plain,
+    /// JSON object
+    Json,
+    /// `TERMBG_KEY=value` pairs in a line
+    Env,
+    /// `export TERMBG_KEY='value'` lines
+    Shell,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Timeout {
+    Auto,
+    Fixed(Duration),
+}
+
+/// Detection result of a subcommand
+#[derive(Debug, Default)]
+struct Report {
+    /// Output of `--format plain`
+    plain: Vec<String>,
+    /// Output of the other formats
+    fields: Map<String, Value>,
+    /// Theme reflected to the exit code
+    theme: Option<Theme>,
+}
+
+impl Report {
+    fn field<T: Into<Value>>(&mut self, key: &str, value: T) {
+        self.fields.insert(key.to_string(), value.into());
+    }
+}
+
+fn parse_timeout(s: &str) -> Result<Timeout, String> {
+    if s == "auto" {
+        return Ok(Timeout::Auto);
+    }
+    s.parse()
+        .map(|ms| Timeout::Fixed(Duration::from_millis(ms)))
+        .map_err(|_| format!("invalid timeout: {s}"))
+}
+
+fn main() {
+    let opt = Opt::parse();
+
+    if opt.debug {
+        CombinedLogger::init(vec![TermLogger::new(
+            LevelFilter::Debug,
+            Config::default(),
+            TerminalMode::Stderr,
+            ColorChoice::Auto,
+        )])
+        .unwrap();
+    }
+
+    let format = opt.format;
+    let start_time = Instant::now();
+    let (mut report, code) = match run(opt) {
+        Ok(report) => {
+            let code = match report.theme {
+                Some(Theme::Light) => EXIT_LIGHT,
+                _ => EXIT_DARK,
+            };
+            (report, code)
+        }
+        Err(e) => {
+            let (kind, code) = error_kind(&e);
+            if format == Format::Plain {
+                eprintln!("termbg: {e}");
+            }
+            let mut report = Report::default();
+            report.field("error", kind);
+            report.field("message", e.to_string());
+            (report, code)
+        }
+    };
+    report.field("terminal", termbg::terminal().as_str());
+    report.field("elapsed_ms", millis(start_time.elapsed()));
+
+    print!("{}", render(&report, format));
+    process::exit(code);
+}
+
+fn run(opt: Opt) -> Result<Report, Error> {
+    let timeout = match opt.timeout {
+        Timeout::Auto => termbg::auto_timeout(),
+        Timeout::Fixed(timeout) => timeout,
+    };
+
+    let mut report = Report::default();
+    match opt.command {
+        Command::Theme => {
+            let detection = termbg::detect(timeout)?;
+            let theme = detection.theme.as_str();
+            report.plain.push(theme.to_string());
+            report.field("theme", theme);
+            if let Some(rgb) = detection.rgb {
+                report.field("bg", hex(rgb));
+            }
+            report.field("source", detection.source.as_str());
+            report.theme = Some(detection.theme);
+        }
+        Command::Bg => {
+            let bg = hex(termbg::rgb(timeout)?);
+            report.plain.push(bg.clone());
+            report.field("bg", bg);
+        }
+        Command::Fg => {
+            let fg = hex(termbg::fg(timeout)?);
+            report.plain.push(fg.clone());
+            report.field("fg", fg);
+        }
+        Command::Palette { indices } => {
+            let indices = if indices.is_empty() {
+                (0..16).collect()
+            } else {
+                indices
+            };
+            let colors = termbg::palette(&indices, timeout)?;
+            for (index, rgb) in indices.iter().zip(colors) {
+                report.plain.push(format!("{index} {}", hex(rgb)));
+                report.field(&format!("palette_{index}"), hex(rgb));
+            }
+        }
+        Command::Latency { samples } => {
+            let stats = termbg::latency_stats(samples, timeout)?;
+            for (key, latency) in [
+                ("min", stats.min),
+                ("median", stats.median),
+                ("p95", stats.p95),
+                ("max", stats.max),
+            ] {
+                report.plain.push(format!("{key} {:.3}", millis(latency)));
+                report.field(&format!("latency_{key}_ms"), millis(latency));
+            }
+        }
+        Command::Terminal => {
+            report
+                .plain
+                .push(format!("terminal {}", termbg::terminal().as_str()));
+            // Identification is optional because not all terminals answer it
+            if let Ok(info) = termbg::terminal_info(timeout) {
+                if let Some(name) = info.name {
+                    report.plain.push(format!("name {name}"));
+                    report.field("name", name);
+                }
+                if let Some(version) = info.version {
+                    report.plain.push(format!("version {version}"));
+                    report.field("version", version);
+                }
+            }
+        }
+        Command::Diagnose => {
+            let diagnosis = termbg::diagnose(timeout);
+            report.plain.push(diagnosis.to_string());
+            if let Some(theme) = diagnosis.theme {
+                report.field("theme", theme.as_str());
+            }
+            if let Some(rgb) = diagnosis.rgb {
+                report.field("bg", hex(rgb));
+            }
+            if let Some(source) = diagnosis.source {
+                report.field("source", source.as_str());
+            }
+            if let Some(remote) = diagnosis.remote {
+                report.field("remote", remote.as_str());
+            }
+            if let Err(e) = diagnosis.preflight {
+                report.field("preflight", e);
+            }
+            let steps: Vec<_> = diagnosis
+                .steps
+                .iter()
+                .map(|step| {
+                    let mut value = json!({
+                        "source": step.source.as_str(),
+                        "query": step.query,
+                        "response": step.response,
+                        "elapsed_ms": millis(step.elapsed),
+                    });
+                    match &step.result {
+                        Ok(Detected::Theme(theme)) => value["theme"] = theme.as_str().into(),
+                        Ok(Detected::Rgb(rgb)) => value["bg"] = hex(*rgb).into(),
+                        Ok(Detected::Latency(latency)) => {
+                            value["latency_ms"] = millis(*latency).into()
+                        }
+                        Ok(Detected::Output(output)) => value["output"] = output.as_str().into(),
+                        Err(e) => value["error"] = e.as_str().into(),
+                    }
+                    value
+                })
+                .collect();
+            report.field("steps", steps);
+        }
+    }
+    Ok(report)
+}
+
+fn error_kind(e: &Error) -> (&'static str, i32) {
+    match e {
+        Error::Timeout { .. } => ("timeout", EXIT_TIMEOUT),
+        Error::Unsupported
+        | Error::UnsupportedTerminal(_)
+        | Error::DumbTerminal
+        | Error::NotATerminal { .. }
+        | Error::MoshSession => ("unsupported", EXIT_UNSUPPORTED),
+        _ => ("error", EXIT_ERROR),
+    }
+}
+
+fn render(report: &Report, format: Format) -> String {
+    match format {
+        Format::Plain => report.plain.iter().map(|x| format!("{x}\n")).collect(),
+        Format::Json => format!("{}\n", Value::Object(report.fields.clone())),
+        Format::Env => {
+            let pairs: Vec<_> = scalar_fields(report)
+                .map(|(key, value)| format!("{key}={}", quote_if_needed(&value)))
+                .collect();
+            format!("{}\n", pairs.join(" "))
+        }
+        Format::Shell => scalar_fields(report)
+            .map(|(key, value)| format!("export {key}={}\n", quote(&value)))
+            .collect(),
+    }
+}
+
+/// Fields as `TERMBG_KEY` and value, except nested ones
+fn scalar_fields(report: &Report) -> impl Iterator<Item = (String, String)> + '_ {
+    report.fields.iter().filter_map(|(key, value)| {
+        let value = match value {
+            Value::String(x) => x.clone(),
+            Value::Number(x) => x.to_string(),
+            Value::Bool(x) => x.to_string(),
+            _ => return None,
+        };
+        Some((format!("TERMBG_{}", key.to_uppercase()), value))
+    })
+}
+
+fn quote_if_needed(value: &str) -> String {
+    let is_safe = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "#%+,-./:@_".contains(c));
+    if is_safe {
+        value.to_string()
+    } else {
+        quote(value)
+    }
+}
+
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+fn hex(rgb: Rgb) -> String {
+    format!("#{:02x}{:02x}{:02x}", rgb.r >> 8, rgb.g >> 8, rgb.b >> 8)
+}
+
+/// Milliseconds rounded to microseconds
+fn millis(duration: Duration) -> f64 {
+    duration.as_micros() as f64 / 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_timeout() {
+        assert_eq!(parse_timeout("auto"), Ok(Timeout::Auto));
+        assert_eq!(
+            parse_timeout("250"),
+            Ok(Timeout::Fixed(Duration::from_millis(250)))
+        );
+        assert!(parse_timeout("1s").is_err());
+    }
+
+    #[test]
+    fn test_render() {
+        let mut report = Report::default();
+        report.plain.push("dark".to_string());
+        report.field("theme", "dark");
+        report.field("bg", "#1e1e2e");
+        report.field("name", "it's");
+        report.field("steps", Vec::<Value>::new());
+
+        assert_eq!(render(&report, Format::Plain), "dark\n");
+        assert_eq!(
+            render(&report, Format::Env),
+            "TERMBG_BG=#1e1e2e TERMBG_NAME='it'\\''s' TERMBG_THEME=dark\n"
+        );
+        assert_eq!(
+            render(&report, Format::Shell),
+            "export TERMBG_BG='#1e1e2e'\nexport TERMBG_NAME='it'\\''s'\nexport TERMBG_THEME='dark'\n"
+        );
+        assert_eq!(
+            render(&report, Format::Json),
+            "{\"bg\":\"#1e1e2e\",\"name\":\"it's\",\"steps\":[],\"theme\":\"dark\"}\n"
+        );
+    }
+}
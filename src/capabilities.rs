@@ -0,0 +1,315 @@
+use crate::query::build_queries;
+use crate::{
+    default_io, find_da1_reply, query_layers, read_reply, terminal, with_terminal_io, Error,
+    EventReader, Multiplexer, Query, QueryOptions, Terminal, TerminalIo,
+};
+use log::debug;
+use std::fmt::Debug;
+use std::io::Write;
+use std::time::Duration;
+
+/// Terminator of OSC replies
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ReplyTerminator {
+    /// `BEL` (`0x07`)
+    Bel,
+    /// `ST` (`ESC \`)
+    St,
+    /// The terminator is not known in advance
+    Unknown,
+}
+
+impl ReplyTerminator {
+    /// Name in snake_case
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ReplyTerminator::Bel => "bel",
+            ReplyTerminator::St => "st",
+            ReplyTerminator::Unknown => "unknown",
+        }
+    }
+}
+
+/// Wrapping required to pass a query through a terminal multiplexer
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Passthrough {
+    /// The query is sent as is
+    None,
+    /// `DCS tmux; ... ST`
+    Tmux,
+    /// `DCS ... ST`
+    Screen,
+    /// `DCS tmux; ... ST` inside GNU Screen
+    TmuxInScreen,
+}
+
+impl Passthrough {
+    /// Name in snake_case, same as the `Terminal` requiring it
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Passthrough::None => "none",
+            Passthrough::Tmux => "tmux",
+            Passthrough::Screen => "screen",
+            Passthrough::TmuxInScreen => "tmux_in_screen",
+        }
+    }
+}
+
+/// Queries supported by a terminal
+///
+/// `false` means the query is known not to be answered, so waiting for the reply would
+/// only hang until timeout. Unknown terminals are assumed to support all queries.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Foreground color query (`OSC 10 ; ?`)
+    pub osc10: bool,
+    /// Background color query (`OSC 11 ; ?`)
+    pub osc11: bool,
+    /// Palette color query (`OSC 4 ; n ; ?`)
+    pub osc4: bool,
+    /// Primary device attributes (`CSI c`)
+    pub da1: bool,
+    /// Device status report (`CSI 5 n`)
+    pub dsr: bool,
+    /// Color scheme query (`CSI ? 996 n`) and notification (mode 2031)
+    pub color_scheme: bool,
+    /// Terminator of OSC replies
+    pub terminator: ReplyTerminator,
+    /// Wrapping required to reach the terminal emulator
+    pub passthrough: Passthrough,
+    /// Recommended timeout of queries
+    pub timeout: Duration,
+}
+
+impl Capabilities {
+    const NONE: Capabilities = Capabilities {
+        osc10: false,
+        osc11: false,
+        osc4: false,
+        da1: false,
+        dsr: false,
+        color_scheme: false,
+        terminator: ReplyTerminator::Unknown,
+        passthrough: Passthrough::None,
+        timeout: Duration::from_millis(0),
+    };
+
+    const XTERM: Capabilities = Capabilities {
+        osc10: true,
+        osc11: true,
+        osc4: true,
+        da1: true,
+        dsr: true,
+        color_scheme: true,
+        terminator: ReplyTerminator::St,
+        passthrough: Passthrough::None,
+        timeout: Duration::from_millis(100),
+    };
+}
+
+impl From<Terminal> for Capabilities {
+    fn from(term: Terminal) -> Self {
+        let xterm = Capabilities::XTERM;
+        match term {
+            Terminal::Windows | Terminal::Emacs | Terminal::Dumb => Capabilities::NONE,
+            // Multiplexers add a hop, and the reply terminator follows the wrapped query
+            Terminal::Tmux => Capabilities {
+                terminator: ReplyTerminator::Bel,
+                passthrough: Passthrough::Tmux,
+                timeout: Duration::from_millis(200),
+                ..xterm
+            },
+            Terminal::Screen => Capabilities {
+                terminator: ReplyTerminator::Bel,
+                passthrough: Passthrough::Screen,
+                timeout: Duration::from_millis(200),
+                ..xterm
+            },
+            Terminal::TmuxInScreen => Capabilities {
+                terminator: ReplyTerminator::Bel,
+                passthrough: Passthrough::TmuxInScreen,
+                timeout: Duration::from_millis(300),
+                ..xterm
+            },
+            // Zellij answers by itself
+            Terminal::Zellij => Capabilities {
+                color_scheme: false,
+                terminator: ReplyTerminator::Bel,
+                timeout: Duration::from_millis(200),
+                ..xterm
+            },
+            // JediTerm doesn't answer color queries
+            Terminal::JetBrains => Capabilities {
+                osc10: false,
+                osc11: false,
+                osc4: false,
+                color_scheme: false,
+                ..xterm
+            },
+            // The Linux console only answers DA1 and DSR
+            Terminal::LinuxConsole => Capabilities {
+                osc10: false,
+                osc11: false,
+                osc4: false,
+                color_scheme: false,
+                terminator: ReplyTerminator::Unknown,
+                ..xterm
+            },
+            Terminal::VsCode => Capabilities {
+                color_scheme: false,
+                timeout: Duration::from_millis(200),
+                ..xterm
+            },
+            Terminal::Kitty
+            | Terminal::WezTerm
+            | Terminal::Alacritty
+            | Terminal::Foot
+            | Terminal::Vte
+            | Terminal::XtermCompatible => xterm,
+        }
+    }
+}
+
+/// get capabilities of the detected terminal
+pub fn capabilities() -> Capabilities {
+    Capabilities::from(terminal())
+}
+
+/// get capabilities of the detected terminal by querying it
+///
+/// All queries are sent at once, followed by DA1 to detect the end of replies.
+/// If the terminal doesn't answer DA1 within `timeout`, the capabilities of the detected
+/// terminal are returned.
+pub fn probe_capabilities(timeout: Duration) -> Result<Capabilities, Error> {
+    let term = terminal();
+    let caps = Capabilities::from(term);
+    let io = default_io();
+    if !caps.da1 || !io.is_terminal() {
+        return Ok(caps);
+    }
+
+    let layers = query_layers(term);
+
+    with_terminal_io(&io, |event_reader, writer| {
+        query_capabilities(&layers, caps, timeout, event_reader, writer)
+    })
+}
+
+pub(crate) fn query_capabilities<R, W>(
+    layers: &[Multiplexer],
+    caps: Capabilities,
+    timeout: Duration,
+    event_reader: &R,
+    buffer: &mut W,
+) -> Result<Capabilities, Error>
+where
+    R: EventReader + Debug,
+    W: Write + Debug,
+{
+    let query = build_queries(
+        layers,
+        &[
+            Query::Foreground,
+            Query::Background,
+            Query::Palette(0),
+            Query::ColorScheme,
+            Query::Status,
+            Query::DeviceAttributes,
+        ],
+        QueryOptions::default(),
+    );
+
+    // Send query
+    buffer.write_all(&query)?;
+    buffer.flush()?;
+
+    let response = match read_reply(timeout, event_reader, |response| {
+        find_da1_reply(response).is_some()
+    }) {
+        Ok(response) => response,
+        Err(Error::Timeout { .. }) => {
+            debug!("No DA1 reply, so capabilities can't be probed.\r");
+            return Ok(caps);
+        }
+        Err(e) => return Err(e),
+    };
+    debug!("capabilities response={response:?}\r");
+
+    Ok(Capabilities {
+        osc10: response.contains("\x1b]10;"),
+        osc11: response.contains("\x1b]11;"),
+        osc4: response.contains("\x1b]4;"),
+        da1: true,
+        dsr: response.contains("\x1b[0n"),
+        color_scheme: response.contains("\x1b[?997;"),
+        terminator: reply_terminator(&response),
+        ..caps
+    })
+}
+
+// Find the terminator of the first OSC reply
+fn reply_terminator(response: &str) -> ReplyTerminator {
+    let Some(start) = response.find("\x1b]") else {
+        return ReplyTerminator::Unknown;
+    };
+    let rest = &response[start + 2..];
+    match (rest.find('\x07'), rest.find("\x1b\\")) {
+        (Some(bel), Some(st)) if bel < st => ReplyTerminator::Bel,
+        (Some(_), None) => ReplyTerminator::Bel,
+        (_, Some(_)) => ReplyTerminator::St,
+        (None, None) => ReplyTerminator::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{mock_event_reader, mock_writer, reply_events};
+
+    fn run_query_capabilities_test(reply: &str) -> Result<Capabilities, Error> {
+        let mut mock_writer =
+            mock_writer(b"\x1b]10;?\x07\x1b]11;?\x07\x1b]4;0;?\x07\x1b[?996n\x1b[5n\x1b[c");
+
+        let mock_event_reader = mock_event_reader(reply_events(reply));
+
+        query_capabilities(
+            &[],
+            Capabilities::from(Terminal::XtermCompatible),
+            Duration::from_millis(100),
+            &mock_event_reader,
+            &mut mock_writer,
+        )
+    }
+
+    #[test]
+    fn test_query_capabilities() {
+        // Answers OSC 11 and DSR only
+        let caps = run_query_capabilities_test("\x1b]11;rgb:0000/0000/0000\x07\x1b[0n\x1b[?62;22c")
+            .unwrap();
+        assert!(!caps.osc10);
+        assert!(caps.osc11);
+        assert!(!caps.osc4);
+        assert!(caps.dsr);
+        assert!(!caps.color_scheme);
+        assert_eq!(caps.terminator, ReplyTerminator::Bel);
+        assert_eq!(caps.passthrough, Passthrough::None);
+
+        // No DA1 reply
+        let caps = run_query_capabilities_test("\x1b]11;rgb:0000/0000/0000\x07").unwrap();
+        assert_eq!(caps, Capabilities::from(Terminal::XtermCompatible));
+    }
+
+    #[test]
+    fn test_reply_terminator() {
+        assert_eq!(
+            reply_terminator("\x1b]11;rgb:0000/0000/0000\x1b\\"),
+            ReplyTerminator::St
+        );
+        assert_eq!(
+            reply_terminator("\x1b]11;rgb:0000/0000/0000\x07\x1b]10;rgb:ffff/ffff/ffff\x1b\\"),
+            ReplyTerminator::Bel
+        );
+        assert_eq!(reply_terminator("\x1b[?62;22c"), ReplyTerminator::Unknown);
+    }
+}
@@ -0,0 +1,205 @@
+use crate::{pop_key_event, push_key_event, Error, EventReader};
+use crossterm::event::{Event, KeyCode};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+fn disconnected() -> Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "channel disconnected").into()
+}
+
+/// An event reader which receives events from a channel owned by the caller.
+///
+/// This is for applications which already read events by themselves, e.g. a running TUI.
+/// The event loop of the application forwards events to the sender while waiting for the reply.
+/// Events which are not a part of the reply can be taken back by `take_unconsumed()`.
+#[derive(Debug)]
+pub struct ChannelEventReader {
+    receiver: Receiver<Event>,
+    pending: RefCell<Option<Event>>,
+    unconsumed: RefCell<Vec<Event>>,
+}
+
+impl ChannelEventReader {
+    pub fn new(receiver: Receiver<Event>) -> Self {
+        ChannelEventReader {
+            receiver,
+            pending: RefCell::new(None),
+            unconsumed: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Take the events received while waiting for the reply which are not a part of it.
+    pub fn take_unconsumed(&self) -> Vec<Event> {
+        self.unconsumed.take()
+    }
+}
+
+impl EventReader for ChannelEventReader {
+    fn read_event(&self) -> Result<Event, Error> {
+        if let Some(event) = self.pending.borrow_mut().take() {
+            return Ok(event);
+        }
+        self.receiver.recv().map_err(|_| disconnected())
+    }
+
+    fn poll(&self, timeout: Duration) -> Result<bool, Error> {
+        if self.pending.borrow().is_some() {
+            return Ok(true);
+        }
+        match self.receiver.recv_timeout(timeout) {
+            Ok(event) => {
+                *self.pending.borrow_mut() = Some(event);
+                Ok(true)
+            }
+            Err(RecvTimeoutError::Timeout) => Ok(false),
+            Err(RecvTimeoutError::Disconnected) => Err(disconnected()),
+        }
+    }
+
+    fn unconsumed(&self, event: Event) {
+        self.unconsumed.borrow_mut().push(event);
+    }
+}
+
+/// An event reader which receives raw input bytes from a channel owned by the caller.
+///
+/// Bytes are mapped to key events in the same way as the default event reader on Unix,
+/// so CSI replies swallowed by `crossterm` can be read as well.
+/// Bytes which are not a part of the reply can be taken back by `take_unconsumed()`.
+#[derive(Debug)]
+pub struct ByteChannelEventReader {
+    receiver: Receiver<Vec<u8>>,
+    pending: RefCell<VecDeque<u8>>,
+    unconsumed: RefCell<String>,
+}
+
+impl ByteChannelEventReader {
+    pub fn new(receiver: Receiver<Vec<u8>>) -> Self {
+        ByteChannelEventReader {
+            receiver,
+            pending: RefCell::new(VecDeque::new()),
+            unconsumed: RefCell::new(String::new()),
+        }
+    }
+
+    /// Take the bytes received while waiting for the reply which are not a part of it.
+    pub fn take_unconsumed(&self) -> Vec<u8> {
+        self.unconsumed.take().into_bytes()
+    }
+}
+
+impl EventReader for ByteChannelEventReader {
+    fn read_event(&self) -> Result<Event, Error> {
+        while self.pending.borrow().is_empty() {
+            let bytes = self.receiver.recv().map_err(|_| disconnected())?;
+            self.pending.borrow_mut().extend(bytes);
+        }
+        let event = pop_key_event(&mut self.pending.borrow_mut());
+        Ok(event.unwrap_or_else(|| Event::Key(KeyCode::Esc.into())))
+    }
+
+    fn poll(&self, timeout: Duration) -> Result<bool, Error> {
+        if !self.pending.borrow().is_empty() {
+            return Ok(true);
+        }
+        match self.receiver.recv_timeout(timeout) {
+            Ok(bytes) => {
+                let mut pending = self.pending.borrow_mut();
+                pending.extend(bytes);
+                Ok(!pending.is_empty())
+            }
+            Err(RecvTimeoutError::Timeout) => Ok(false),
+            Err(RecvTimeoutError::Disconnected) => Err(disconnected()),
+        }
+    }
+
+    fn unconsumed(&self, event: Event) {
+        // Bytes are mapped to key events only
+        if let Event::Key(key_event) = &event {
+            push_key_event(&mut self.unconsumed.borrow_mut(), key_event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{query_background_with, tests::reply_events, Rgb};
+    use crossterm::event::{KeyEvent, KeyModifiers};
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_channel_event_reader() {
+        let (sender, receiver) = mpsc::channel();
+        let event_reader = ChannelEventReader::new(receiver);
+        // Keys typed while waiting for the reply don't end it
+        let typed = [
+            Event::Key(KeyCode::Char('\\').into()),
+            Event::Resize(80, 24),
+            Event::Key(KeyCode::Up.into()),
+        ];
+        for event in typed
+            .iter()
+            .cloned()
+            .chain(reply_events("\x1b]11;rgb:1e1e/1e1e/2e2e\x1b\\"))
+        {
+            sender.send(event).unwrap();
+        }
+
+        let mut buffer = Vec::new();
+        let rgb =
+            query_background_with(&[], Duration::from_millis(100), &event_reader, &mut buffer);
+        assert_eq!(buffer, b"\x1b]11;?\x1b\\");
+        assert_eq!(
+            rgb.unwrap(),
+            Rgb {
+                r: 0x1e1e,
+                g: 0x1e1e,
+                b: 0x2e2e
+            }
+        );
+        assert_eq!(event_reader.take_unconsumed(), typed);
+        assert!(event_reader.take_unconsumed().is_empty());
+        assert!(!event_reader.poll(Duration::from_millis(1)).unwrap());
+
+        drop(sender);
+        assert!(event_reader.poll(Duration::from_millis(1)).is_err());
+    }
+
+    #[test]
+    fn test_byte_channel_event_reader() {
+        let (sender, receiver) = mpsc::channel();
+        let event_reader = ByteChannelEventReader::new(receiver);
+        sender.send(b"k\x07\x1b]11;rgb:ffff/".to_vec()).unwrap();
+        sender.send(Vec::new()).unwrap();
+        sender.send(b"ffff/ffff\x1b\\q".to_vec()).unwrap();
+
+        let mut buffer = Vec::new();
+        let rgb =
+            query_background_with(&[], Duration::from_millis(100), &event_reader, &mut buffer);
+        assert_eq!(
+            rgb.unwrap(),
+            Rgb {
+                r: 0xffff,
+                g: 0xffff,
+                b: 0xffff
+            }
+        );
+        assert_eq!(event_reader.take_unconsumed(), b"k\x07");
+        // Input after the reply is left to the caller
+        assert!(event_reader.poll(Duration::from_millis(1)).unwrap());
+        assert_eq!(
+            event_reader.read_event().unwrap(),
+            Event::Key(KeyEvent::new(KeyCode::Char('q'), KeyModifiers::NONE))
+        );
+
+        sender.send(b"\x1b".to_vec()).unwrap();
+        assert_eq!(
+            event_reader.read_event().unwrap(),
+            Event::Key(KeyCode::Esc.into())
+        );
+    }
+}
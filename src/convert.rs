@@ -0,0 +1,188 @@
+//! Conversions between `Rgb` and color types of other crates
+//!
+//! 16bit colors are truncated to 8bit by the upper byte, and 8bit colors are expanded to
+//! 16bit as `0xff` -> `0xffff`.
+
+use crate::{Error, Rgb};
+use std::convert::TryFrom;
+
+fn to_8bit(x: u16) -> u8 {
+    (x >> 8) as u8
+}
+
+fn to_16bit(x: u8) -> u16 {
+    u16::from(x) * 0x101
+}
+
+impl From<Rgb> for crossterm::style::Color {
+    fn from(rgb: Rgb) -> Self {
+        crossterm::style::Color::Rgb {
+            r: to_8bit(rgb.r),
+            g: to_8bit(rgb.g),
+            b: to_8bit(rgb.b),
+        }
+    }
+}
+
+/// Only `Color::Rgb` is converted because other colors depend on the palette of the terminal.
+impl TryFrom<crossterm::style::Color> for Rgb {
+    type Error = Error;
+
+    fn try_from(color: crossterm::style::Color) -> Result<Self, Error> {
+        match color {
+            crossterm::style::Color::Rgb { r, g, b } => Ok(Rgb {
+                r: to_16bit(r),
+                g: to_16bit(g),
+                b: to_16bit(b),
+            }),
+            _ => Err(Error::Unsupported),
+        }
+    }
+}
+
+#[cfg(feature = "ratatui")]
+impl From<Rgb> for ratatui::style::Color {
+    fn from(rgb: Rgb) -> Self {
+        ratatui::style::Color::Rgb(to_8bit(rgb.r), to_8bit(rgb.g), to_8bit(rgb.b))
+    }
+}
+
+/// Only `Color::Rgb` is converted because other colors depend on the palette of the terminal.
+#[cfg(feature = "ratatui")]
+impl TryFrom<ratatui::style::Color> for Rgb {
+    type Error = Error;
+
+    fn try_from(color: ratatui::style::Color) -> Result<Self, Error> {
+        match color {
+            ratatui::style::Color::Rgb(r, g, b) => Ok(Rgb {
+                r: to_16bit(r),
+                g: to_16bit(g),
+                b: to_16bit(b),
+            }),
+            _ => Err(Error::Unsupported),
+        }
+    }
+}
+
+#[cfg(feature = "anstyle")]
+impl From<Rgb> for anstyle::RgbColor {
+    fn from(rgb: Rgb) -> Self {
+        anstyle::RgbColor(to_8bit(rgb.r), to_8bit(rgb.g), to_8bit(rgb.b))
+    }
+}
+
+#[cfg(feature = "anstyle")]
+impl From<anstyle::RgbColor> for Rgb {
+    fn from(color: anstyle::RgbColor) -> Self {
+        Rgb {
+            r: to_16bit(color.0),
+            g: to_16bit(color.1),
+            b: to_16bit(color.2),
+        }
+    }
+}
+
+#[cfg(feature = "palette")]
+impl From<Rgb> for palette::Srgb<u16> {
+    fn from(rgb: Rgb) -> Self {
+        palette::Srgb::new(rgb.r, rgb.g, rgb.b)
+    }
+}
+
+#[cfg(feature = "palette")]
+impl From<palette::Srgb<u16>> for Rgb {
+    fn from(color: palette::Srgb<u16>) -> Self {
+        Rgb {
+            r: color.red,
+            g: color.green,
+            b: color.blue,
+        }
+    }
+}
+
+#[cfg(feature = "rgb")]
+impl From<Rgb> for rgb::RGB16 {
+    fn from(rgb: Rgb) -> Self {
+        rgb::RGB16::new(rgb.r, rgb.g, rgb.b)
+    }
+}
+
+#[cfg(feature = "rgb")]
+impl From<rgb::RGB16> for Rgb {
+    fn from(color: rgb::RGB16) -> Self {
+        Rgb {
+            r: color.r,
+            g: color.g,
+            b: color.b,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RGB: Rgb = Rgb {
+        r: 0x1e1e,
+        g: 0x2e2e,
+        b: 0xffff,
+    };
+
+    #[test]
+    fn test_crossterm() {
+        let color = crossterm::style::Color::from(RGB);
+        assert_eq!(
+            color,
+            crossterm::style::Color::Rgb {
+                r: 0x1e,
+                g: 0x2e,
+                b: 0xff
+            }
+        );
+        assert_eq!(Rgb::try_from(color).unwrap(), RGB);
+        assert!(Rgb::try_from(crossterm::style::Color::Reset).is_err());
+    }
+
+    #[cfg(feature = "ratatui")]
+    #[test]
+    fn test_ratatui() {
+        let color = ratatui::style::Color::from(RGB);
+        assert_eq!(color, ratatui::style::Color::Rgb(0x1e, 0x2e, 0xff));
+        assert_eq!(Rgb::try_from(color).unwrap(), RGB);
+        assert!(Rgb::try_from(ratatui::style::Color::Indexed(1)).is_err());
+    }
+
+    #[cfg(feature = "anstyle")]
+    #[test]
+    fn test_anstyle() {
+        let color = anstyle::RgbColor::from(RGB);
+        assert_eq!(color, anstyle::RgbColor(0x1e, 0x2e, 0xff));
+        assert_eq!(Rgb::from(color), RGB);
+    }
+
+    #[cfg(feature = "palette")]
+    #[test]
+    fn test_palette() {
+        let rgb = Rgb {
+            r: 0x1234,
+            g: 0x5678,
+            b: 0xabcd,
+        };
+        let color = palette::Srgb::<u16>::from(rgb);
+        assert_eq!(color, palette::Srgb::new(0x1234, 0x5678, 0xabcd));
+        assert_eq!(Rgb::from(color), rgb);
+    }
+
+    #[cfg(feature = "rgb")]
+    #[test]
+    fn test_rgb() {
+        let rgb = Rgb {
+            r: 0x1234,
+            g: 0x5678,
+            b: 0xabcd,
+        };
+        let color = rgb::RGB16::from(rgb);
+        assert_eq!(color, rgb::RGB16::new(0x1234, 0x5678, 0xabcd));
+        assert_eq!(Rgb::from(color), rgb);
+    }
+}
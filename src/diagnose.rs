@@ -0,0 +1,540 @@
+use crate::terminal_io::{IoEventReader, IoWriter};
+#[cfg(not(target_os = "windows"))]
+use crate::TMUX_CLIENT_FORMAT;
+use crate::{
+    adapt_timeout, capabilities::Capabilities, default_io, from_env_colorfgbg, preflight_fallback,
+    push_key_event, query_color_scheme, query_latency, query_layers, query_osc_color, query_xterm,
+    remote, rgb_to_theme, terminal, with_terminal_io, ColorSlot, CrosstermEventReader, Error,
+    EventReader, Remote, Rgb, StdioIo, Terminal, TerminalIo, Theme, REMOTE_PROBE_TIMEOUT,
+};
+use crossterm::event::Event;
+use std::cell::RefCell;
+use std::env;
+use std::fmt;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+/// Environment variables consulted by detection
+const ENV_VARS: &[&str] = &[
+    "TERM",
+    "INSIDE_EMACS",
+    "ZELLIJ",
+    "ZELLIJ_SESSION_NAME",
+    "TMUX",
+    "STY",
+    "SSH_CONNECTION",
+    "SSH_TTY",
+    "TERM_PROGRAM",
+    "TERMINAL_EMULATOR",
+    "KITTY_WINDOW_ID",
+    "WEZTERM_PANE",
+    "ALACRITTY_WINDOW_ID",
+    "VTE_VERSION",
+    "COLORFGBG",
+    "NO_COLOR",
+    "CI",
+];
+
+/// Detection strategy which determined the result, or a step preparing the detection
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+#[non_exhaustive]
+pub enum Source {
+    /// Color scheme reported by `CSI ? 996 n`
+    ColorScheme,
+    /// Background color reported by `OSC 11`
+    Osc11,
+    /// Background color reported by tmux itself
+    Tmux,
+    /// `window-style` configured in tmux
+    TmuxStyle,
+    /// Background of the default face asked to Emacs
+    Emacs,
+    /// `COLORFGBG` environment variable
+    Colorfgbg,
+    /// Legacy Windows Console API
+    WinApi,
+    /// Latency probed by `CSI 5 n` to extend the timeout over SSH
+    Latency,
+    /// `allow-passthrough` option of tmux
+    TmuxPassthrough,
+    /// Terminal of the client attached to tmux over SSH
+    TmuxClient,
+}
+
+impl Source {
+    /// Name in snake_case, same as the serialized one
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Source::ColorScheme => "color_scheme",
+            Source::Osc11 => "osc11",
+            Source::Tmux => "tmux",
+            Source::TmuxStyle => "tmux_style",
+            Source::Emacs => "emacs",
+            Source::Colorfgbg => "colorfgbg",
+            Source::WinApi => "win_api",
+            Source::Latency => "latency",
+            Source::TmuxPassthrough => "tmux_passthrough",
+            Source::TmuxClient => "tmux_client",
+        }
+    }
+}
+
+/// Value detected by a detection step
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Detected {
+    Theme(Theme),
+    Rgb(Rgb),
+    /// Round trip of `CSI 5 n`
+    Latency(Duration),
+    /// Output of a command
+    Output(String),
+}
+
+/// Report of a detection step
+#[derive(Clone, Debug)]
+pub struct Step {
+    /// Detection strategy
+    pub source: Source,
+    /// Query sent to the terminal, or the command run
+    pub query: String,
+    /// Raw reply received from the terminal, or the output of the command
+    pub response: String,
+    /// Time spent by this step
+    pub elapsed: Duration,
+    /// Parse result, or the error message
+    pub result: Result<Detected, String>,
+}
+
+/// Report of every detection step
+#[derive(Clone, Debug)]
+pub struct Diagnosis {
+    /// Environment variables consulted by detection
+    pub env: Vec<(&'static str, Option<String>)>,
+    /// Detected terminal
+    pub terminal: Terminal,
+    /// Capabilities of the detected terminal
+    pub capabilities: Capabilities,
+    /// Detected remote session
+    pub remote: Option<Remote>,
+    /// The reason why detection was not attempted
+    pub preflight: Result<(), String>,
+    /// Detection steps in the order of execution
+    pub steps: Vec<Step>,
+    /// Detected theme
+    pub theme: Option<Theme>,
+    /// Detected background color
+    pub rgb: Option<Rgb>,
+    /// Detection strategy which determined the theme
+    pub source: Option<Source>,
+}
+
+/// run every detection strategy and report how the result was determined
+pub fn diagnose(timeout: Duration) -> Diagnosis {
+    let env: Vec<_> = ENV_VARS
+        .iter()
+        .map(|key| (*key, env::var(key).ok()))
+        .collect();
+    let term = terminal();
+    let capabilities = Capabilities::from(term);
+    let remote = remote();
+    let preflight = preflight_fallback().map_err(|e| e.to_string());
+
+    let mut diagnosis = Diagnosis {
+        env,
+        terminal: term,
+        capabilities,
+        remote,
+        preflight,
+        steps: Vec::new(),
+        theme: None,
+        rgb: None,
+        source: None,
+    };
+
+    if diagnosis.preflight.is_ok() {
+        let steps = &mut diagnosis.steps;
+
+        // tmux is asked for the multiplexers which queries are passed through
+        #[cfg(not(target_os = "windows"))]
+        let tmux = matches!(term, Terminal::Tmux | Terminal::TmuxInScreen);
+        #[cfg(target_os = "windows")]
+        let tmux = false;
+        #[cfg(not(target_os = "windows"))]
+        {
+            if tmux {
+                steps.push(run_tmux_step(
+                    Source::TmuxPassthrough,
+                    crate::tmux::PASSTHROUGH_FORMAT,
+                ));
+            }
+            let is_ssh = env::var("SSH_CONNECTION").is_ok() || env::var("SSH_TTY").is_ok();
+            if term == Terminal::Tmux && is_ssh {
+                steps.push(run_tmux_step(Source::TmuxClient, TMUX_CLIENT_FORMAT));
+            }
+        }
+        let layers = query_layers(term);
+        // tmux is asked by itself as a fallback, or instead of OSC 11 if passthrough is disabled
+        #[cfg(not(target_os = "windows"))]
+        let tmux_only = tmux && !crate::tmux::allows_passthrough();
+        #[cfg(target_os = "windows")]
+        let tmux_only = false;
+
+        // Same as `remote_timeout()`, but probed again to be reported
+        let mut timeout = timeout;
+        if remote == Some(Remote::Ssh) && capabilities.dsr {
+            let step = run_io_step(Source::Latency, &default_io(), |r, w| {
+                query_latency(&layers, REMOTE_PROBE_TIMEOUT, r, w).map(Detected::Latency)
+            });
+            if let Ok(Detected::Latency(latency)) = step.result {
+                timeout = adapt_timeout(timeout, latency);
+            }
+            steps.push(step);
+        }
+
+        // Each query enters raw mode by itself as `detect()` does, so each can fail separately
+        if capabilities.color_scheme && capabilities.da1 {
+            steps.push(run_io_step(Source::ColorScheme, &default_io(), |r, w| {
+                query_color_scheme(&layers, timeout, r, w).map(Detected::Theme)
+            }));
+        }
+        let osc11 = capabilities.osc11
+            && !tmux_only
+            && (term == Terminal::Zellij || remote != Some(Remote::Mosh));
+        if osc11 {
+            let step = if term == Terminal::Zellij {
+                run_io_step(Source::Osc11, &default_io(), |r, w| {
+                    query_osc_color(&layers, ColorSlot::Background, timeout, r, w)
+                        .map(Detected::Rgb)
+                })
+            } else {
+                run_io_step(
+                    Source::Osc11,
+                    &StdioIo::new(CrosstermEventReader),
+                    |r, w| query_xterm(&layers, timeout, r, w).map(Detected::Rgb),
+                )
+            };
+            steps.push(step);
+        }
+        if tmux {
+            steps.push(run_io_step(
+                Source::Tmux,
+                &StdioIo::new(CrosstermEventReader),
+                |r, w| query_xterm(&[], timeout, r, w).map(Detected::Rgb),
+            ));
+        }
+
+        #[cfg(not(target_os = "windows"))]
+        if tmux {
+            diagnosis.steps.push(run_env_step(
+                Source::TmuxStyle,
+                crate::tmux::from_tmux_style,
+            ));
+        }
+
+        if term == Terminal::Emacs {
+            diagnosis.steps.push(run_env_step(Source::Emacs, || {
+                crate::emacs::from_emacs(timeout).map(|(rgb, _)| rgb)
+            }));
+        }
+
+        #[cfg(target_os = "windows")]
+        if term == Terminal::Windows {
+            diagnosis
+                .steps
+                .push(run_env_step(Source::WinApi, crate::from_winapi));
+        }
+
+        diagnosis
+            .steps
+            .push(run_env_step(Source::Colorfgbg, from_env_colorfgbg));
+    }
+
+    conclude(&mut diagnosis);
+
+    diagnosis
+}
+
+/// Determine the result with the same priority as `theme()`
+fn conclude(diagnosis: &mut Diagnosis) {
+    for step in &diagnosis.steps {
+        match step.result {
+            Ok(Detected::Theme(theme)) if diagnosis.theme.is_none() => {
+                diagnosis.theme = Some(theme);
+                diagnosis.source = Some(step.source);
+            }
+            Ok(Detected::Rgb(rgb)) if diagnosis.rgb.is_none() => {
+                diagnosis.rgb = Some(rgb);
+                if diagnosis.theme.is_none() {
+                    diagnosis.theme = Some(rgb_to_theme(rgb));
+                    diagnosis.source = Some(step.source);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+fn run_step<R, W, F>(source: Source, event_reader: &R, writer: &mut W, f: F) -> Step
+where
+    R: EventReader + fmt::Debug,
+    W: Write + fmt::Debug,
+    F: FnOnce(&RecordingEventReader<'_, R>, &mut RecordingWriter<'_, W>) -> Result<Detected, Error>,
+{
+    let reader = RecordingEventReader {
+        inner: event_reader,
+        response: RefCell::new(String::new()),
+    };
+    let mut writer = RecordingWriter {
+        inner: writer,
+        query: Vec::new(),
+    };
+    let start_time = Instant::now();
+    let result = f(&reader, &mut writer).map_err(|e| e.to_string());
+    Step {
+        source,
+        query: String::from_utf8_lossy(&writer.query).into_owned(),
+        response: reader.response.into_inner(),
+        elapsed: start_time.elapsed(),
+        result,
+    }
+}
+
+/// Run `f` in raw mode, or report the failure to enter it as the step.
+fn run_io_step<T, F>(source: Source, io: &T, f: F) -> Step
+where
+    T: TerminalIo + fmt::Debug,
+    F: FnOnce(
+        &RecordingEventReader<'_, IoEventReader<'_, T>>,
+        &mut RecordingWriter<'_, IoWriter<'_, T>>,
+    ) -> Result<Detected, Error>,
+{
+    let start_time = Instant::now();
+    with_terminal_io(io, |event_reader, writer| {
+        Ok(run_step(source, event_reader, writer, f))
+    })
+    .unwrap_or_else(|e| Step {
+        source,
+        query: String::new(),
+        response: String::new(),
+        elapsed: start_time.elapsed(),
+        result: Err(e.to_string()),
+    })
+}
+
+/// Run `tmux display-message -p format`.
+#[cfg(not(target_os = "windows"))]
+fn run_tmux_step(source: Source, format: &str) -> Step {
+    let start_time = Instant::now();
+    let output = std::process::Command::new("tmux")
+        .args(["display-message", "-p", format])
+        .output();
+    let (response, result) = match output {
+        Ok(output) if output.status.success() => {
+            let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+            let result = Ok(Detected::Output(stdout.trim().to_string()));
+            (stdout, result)
+        }
+        Ok(output) => (
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+            Err(format!("tmux exited with {}", output.status)),
+        ),
+        Err(e) => (String::new(), Err(Error::from(e).to_string())),
+    };
+    Step {
+        source,
+        query: format!("tmux display-message -p {format}"),
+        response,
+        elapsed: start_time.elapsed(),
+        result,
+    }
+}
+
+fn run_env_step<F>(source: Source, f: F) -> Step
+where
+    F: FnOnce() -> Result<Rgb, Error>,
+{
+    let start_time = Instant::now();
+    let result = f().map(Detected::Rgb).map_err(|e| e.to_string());
+    Step {
+        source,
+        query: String::new(),
+        response: String::new(),
+        elapsed: start_time.elapsed(),
+        result,
+    }
+}
+
+/// Event reader recording the reconstituted reply
+#[derive(Debug)]
+struct RecordingEventReader<'a, R> {
+    inner: &'a R,
+    response: RefCell<String>,
+}
+
+impl<R: EventReader> EventReader for RecordingEventReader<'_, R> {
+    fn read_event(&self) -> Result<Event, Error> {
+        let event = self.inner.read_event()?;
+        if let Event::Key(key_event) = &event {
+            push_key_event(&mut self.response.borrow_mut(), key_event);
+        }
+        Ok(event)
+    }
+
+    fn poll(&self, timeout: Duration) -> Result<bool, Error> {
+        self.inner.poll(timeout)
+    }
+}
+
+/// Writer recording the query
+#[derive(Debug)]
+struct RecordingWriter<'a, W> {
+    inner: &'a mut W,
+    query: Vec<u8>,
+}
+
+impl<W: Write> Write for RecordingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.query.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl fmt::Display for Diagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Environment:")?;
+        for (key, value) in &self.env {
+            match value {
+                Some(value) => writeln!(f, "  {key}={value}")?,
+                None => writeln!(f, "  {key} (unset)")?,
+            }
+        }
+        writeln!(f, "Terminal: {}", self.terminal.as_str())?;
+        let caps = &self.capabilities;
+        let queries: Vec<_> = [
+            ("osc10", caps.osc10),
+            ("osc11", caps.osc11),
+            ("osc4", caps.osc4),
+            ("da1", caps.da1),
+            ("dsr", caps.dsr),
+            ("color_scheme", caps.color_scheme),
+        ]
+        .iter()
+        .filter_map(|(name, supported)| supported.then_some(*name))
+        .collect();
+        writeln!(
+            f,
+            "Capabilities: [{}] (terminator: {}, passthrough: {}, timeout: {:.2?})",
+            queries.join(", "),
+            caps.terminator.as_str(),
+            caps.passthrough.as_str(),
+            caps.timeout
+        )?;
+        if let Some(remote) = self.remote {
+            writeln!(f, "Remote: {}", remote.as_str())?;
+        }
+        if let Err(e) = &self.preflight {
+            writeln!(f, "Detection not attempted: {e}")?;
+        }
+        for step in &self.steps {
+            writeln!(f, "Step {} ({:.2?}):", step.source.as_str(), step.elapsed)?;
+            if !step.query.is_empty() {
+                writeln!(f, "  sent    : {:?}", step.query)?;
+                writeln!(f, "  received: {:?}", step.response)?;
+            }
+            match &step.result {
+                Ok(Detected::Theme(theme)) => writeln!(f, "  result  : {}", theme.as_str())?,
+                Ok(Detected::Rgb(rgb)) => {
+                    writeln!(f, "  result  : R={:x}, G={:x}, B={:x}", rgb.r, rgb.g, rgb.b)?
+                }
+                Ok(Detected::Latency(latency)) => writeln!(f, "  result  : {latency:.2?}")?,
+                Ok(Detected::Output(output)) => writeln!(f, "  result  : {output:?}")?,
+                Err(e) => writeln!(f, "  error   : {e}")?,
+            }
+        }
+        match (self.theme, self.source) {
+            (Some(theme), Some(source)) => {
+                writeln!(f, "Theme: {} (from {})", theme.as_str(), source.as_str())
+            }
+            _ => writeln!(f, "Theme: not detected"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(source: Source, result: Result<Detected, String>) -> Step {
+        Step {
+            source,
+            query: String::new(),
+            response: String::new(),
+            elapsed: Duration::from_millis(0),
+            result,
+        }
+    }
+
+    fn run_conclude_test(steps: Vec<Step>) -> Diagnosis {
+        let mut diagnosis = Diagnosis {
+            env: Vec::new(),
+            terminal: Terminal::XtermCompatible,
+            capabilities: Capabilities::from(Terminal::XtermCompatible),
+            remote: None,
+            preflight: Ok(()),
+            steps,
+            theme: None,
+            rgb: None,
+            source: None,
+        };
+        conclude(&mut diagnosis);
+        diagnosis
+    }
+
+    #[test]
+    fn test_conclude() {
+        let white = Rgb {
+            r: 0xffff,
+            g: 0xffff,
+            b: 0xffff,
+        };
+        let black = Rgb { r: 0, g: 0, b: 0 };
+
+        // Color scheme wins over the background color
+        let diagnosis = run_conclude_test(vec![
+            step(Source::ColorScheme, Ok(Detected::Theme(Theme::Dark))),
+            step(Source::Osc11, Ok(Detected::Rgb(white))),
+        ]);
+        assert_eq!(diagnosis.theme, Some(Theme::Dark));
+        assert_eq!(diagnosis.rgb, Some(white));
+        assert_eq!(diagnosis.source, Some(Source::ColorScheme));
+        let report = diagnosis.to_string();
+        assert!(report.contains("Terminal: xterm_compatible"));
+        assert!(report.contains("Step color_scheme ("));
+        assert!(report.contains("Theme: dark (from color_scheme)"));
+
+        // Fallback to COLORFGBG
+        let diagnosis = run_conclude_test(vec![
+            step(Source::ColorScheme, Err("unsupported".to_string())),
+            step(Source::Osc11, Err("timeout".to_string())),
+            step(Source::Colorfgbg, Ok(Detected::Rgb(black))),
+        ]);
+        assert_eq!(diagnosis.theme, Some(Theme::Dark));
+        assert_eq!(diagnosis.rgb, Some(black));
+        assert_eq!(diagnosis.source, Some(Source::Colorfgbg));
+
+        let diagnosis = run_conclude_test(vec![step(
+            Source::Colorfgbg,
+            Err("unsupported".to_string()),
+        )]);
+        assert_eq!(diagnosis.theme, None);
+        assert_eq!(diagnosis.source, None);
+        assert!(diagnosis.to_string().contains("Theme: not detected"));
+    }
+}
@@ -0,0 +1,161 @@
+use crate::{from_xterm, CrosstermEventReader, Error, Rgb, Source, StdioIo, Terminal};
+use log::debug;
+use std::env;
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Lower bound of the timeout of `emacsclient`, which includes starting the process
+const EMACSCLIENT_TIMEOUT_MIN: Duration = Duration::from_millis(500);
+
+/// Terminal mode of Emacs, detected from `INSIDE_EMACS`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum EmacsMode {
+    /// `vterm`, which answers OSC 11
+    Vterm,
+    /// `eat`, which answers OSC 11
+    Eat,
+    /// `term`, `M-x shell`, `eshell` and others, which don't answer OSC 11
+    Other,
+}
+
+/// `INSIDE_EMACS` is `vterm` for vterm, and `<emacs-version>,<mode>` for other modes.
+fn emacs_mode(inside_emacs: &str) -> EmacsMode {
+    if inside_emacs == "vterm" {
+        return EmacsMode::Vterm;
+    }
+    match inside_emacs.split_once(',').map(|(_, mode)| mode) {
+        Some("eat") => EmacsMode::Eat,
+        _ => EmacsMode::Other,
+    }
+}
+
+/// Get the background color inside Emacs.
+///
+/// vterm and eat answer OSC 11 as xterm does. If OSC 11 is not answered, the running Emacs
+/// is asked for the background of the default face through `emacsclient`.
+pub(crate) fn from_emacs(timeout: Duration) -> Result<(Rgb, Source), Error> {
+    let mode = emacs_mode(&env::var("INSIDE_EMACS").unwrap_or_default());
+    debug!("emacs mode={mode:?}\r");
+    if mode != EmacsMode::Other {
+        let rgb = from_xterm(
+            &StdioIo::new(CrosstermEventReader),
+            Terminal::XtermCompatible,
+            timeout,
+        );
+        debug!("rgb answered by {mode:?}={rgb:?}\r");
+        if let Ok(rgb) = rgb {
+            return Ok((rgb, Source::Osc11));
+        }
+    }
+    from_emacsclient(timeout.max(EMACSCLIENT_TIMEOUT_MIN)).map(|rgb| (rgb, Source::Emacs))
+}
+
+/// Ask the running Emacs by `emacsclient`.
+///
+/// `emacsclient` starts an alternate editor if Emacs server is not running, which is
+/// disabled by `--alternate-editor=false`.
+fn from_emacsclient(timeout: Duration) -> Result<Rgb, Error> {
+    let mut child = Command::new("emacsclient")
+        .args([
+            "--alternate-editor=false",
+            "--eval",
+            "(color-values (face-background 'default))",
+        ])
+        .env_remove("ALTERNATE_EDITOR")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    // `emacsclient` blocks while Emacs is busy, so give up on timeout
+    let start_time = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if start_time.elapsed() > timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(Error::Timeout {
+                elapsed: start_time.elapsed(),
+                partial_response: String::new(),
+            });
+        }
+        sleep(Duration::from_millis(5));
+    };
+    if !status.success() {
+        return Err(Error::Unsupported);
+    }
+
+    let mut output = String::new();
+    if let Some(mut stdout) = child.stdout.take() {
+        stdout.read_to_string(&mut output)?;
+    }
+    debug!("emacsclient output={output:?}\r");
+    parse_color_values(&output)
+}
+
+/// Parse the output of `color-values` like `(7710 7710 7710)`.
+fn parse_color_values(output: &str) -> Result<Rgb, Error> {
+    let malformed = || Error::MalformedReply {
+        raw: output.to_string(),
+    };
+    let output = output.trim();
+    if output == "nil" {
+        // The default face has no background, e.g. in a tty frame
+        return Err(Error::Unsupported);
+    }
+    let values = output
+        .strip_prefix('(')
+        .and_then(|x| x.strip_suffix(')'))
+        .ok_or_else(malformed)?;
+    let values = values
+        .split_whitespace()
+        .map(|x| x.parse::<u16>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| malformed())?;
+    match values[..] {
+        [r, g, b] => Ok(Rgb { r, g, b }),
+        _ => Err(malformed()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_emacs_mode() {
+        assert_eq!(emacs_mode("vterm"), EmacsMode::Vterm);
+        assert_eq!(emacs_mode("29.1,eat"), EmacsMode::Eat);
+        assert_eq!(emacs_mode("29.1,term:0.96"), EmacsMode::Other);
+        assert_eq!(emacs_mode("29.1,comint"), EmacsMode::Other);
+        assert_eq!(emacs_mode("t"), EmacsMode::Other);
+    }
+
+    #[test]
+    fn test_parse_color_values() {
+        assert_eq!(
+            parse_color_values("(7710 7710 11822)\n").unwrap(),
+            Rgb {
+                r: 7710,
+                g: 7710,
+                b: 11822
+            }
+        );
+        assert!(matches!(
+            parse_color_values("nil\n"),
+            Err(Error::Unsupported)
+        ));
+        assert!(matches!(
+            parse_color_values("(7710 7710)"),
+            Err(Error::MalformedReply { .. })
+        ));
+        assert!(matches!(
+            parse_color_values("\"#1e1e2e\""),
+            Err(Error::MalformedReply { .. })
+        ));
+    }
+}
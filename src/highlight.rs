@@ -0,0 +1,101 @@
+use crate::{detect, rgb_to_theme, Rgb, Theme};
+use log::debug;
+use std::time::Duration;
+use syntect::highlighting::{Color, Theme as SyntectTheme, ThemeSet};
+
+/// select the syntect theme suited to the terminal background from `themes`
+///
+/// Themes are classified into light and dark by their declared background color.
+/// Among the themes of the same `theme`, the one whose background is the nearest to `rgb` is
+/// selected. If `rgb` is not known, the first one by name is selected.
+/// Themes without background color are not selected.
+pub fn select_syntect_theme(
+    themes: &ThemeSet,
+    theme: Theme,
+    rgb: Option<Rgb>,
+) -> Option<(&str, &SyntectTheme)> {
+    let candidates = themes.themes.iter().filter_map(|(name, x)| {
+        let bg = to_rgb(x.settings.background?);
+        if rgb_to_theme(bg) == theme {
+            Some((name.as_str(), x, bg))
+        } else {
+            None
+        }
+    });
+    let (name, selected, _) = match rgb {
+        Some(rgb) => candidates.min_by_key(|(_, _, bg)| distance(*bg, rgb)),
+        None => candidates.min_by_key(|(name, _, _)| *name),
+    }?;
+    debug!("syntect theme={name}\r");
+    Some((name, selected))
+}
+
+/// detect the terminal background and select the syntect theme suited to it from `themes`
+pub fn detect_syntect_theme(themes: &ThemeSet, timeout: Duration) -> Option<(&str, &SyntectTheme)> {
+    let detection = detect(timeout).ok()?;
+    select_syntect_theme(themes, detection.theme, detection.rgb)
+}
+
+fn to_rgb(color: Color) -> Rgb {
+    Rgb {
+        r: u16::from(color.r) * 0x101,
+        g: u16::from(color.g) * 0x101,
+        b: u16::from(color.b) * 0x101,
+    }
+}
+
+/// Squared euclidean distance in 8bit RGB
+fn distance(a: Rgb, b: Rgb) -> u32 {
+    [(a.r, b.r), (a.g, b.g), (a.b, b.b)]
+        .iter()
+        .map(|(x, y)| {
+            let d = i32::from(x >> 8) - i32::from(y >> 8);
+            (d * d) as u32
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a theme set from names and backgrounds like `0x1e1e2e`
+    fn theme_set(backgrounds: &[(&str, Option<u32>)]) -> ThemeSet {
+        let mut themes = ThemeSet::new();
+        for (name, bg) in backgrounds {
+            let mut theme = SyntectTheme::default();
+            theme.settings.background = bg.map(|bg| Color {
+                r: (bg >> 16) as u8,
+                g: (bg >> 8) as u8,
+                b: bg as u8,
+                a: 0xff,
+            });
+            themes.themes.insert(name.to_string(), theme);
+        }
+        themes
+    }
+
+    #[test]
+    fn test_select_syntect_theme() {
+        let themes = theme_set(&[
+            ("Solarized (dark)", Some(0x002b36)),
+            ("Solarized (light)", Some(0xfdf6e3)),
+            ("base16-ocean.dark", Some(0x2b303b)),
+            ("InspiredGitHub", Some(0xffffff)),
+            ("no-background", None),
+        ]);
+        let name = |theme, rgb| select_syntect_theme(&themes, theme, rgb).map(|(name, _)| name);
+
+        let ocean = Rgb {
+            r: 0x2b2b,
+            g: 0x3030,
+            b: 0x3b3b,
+        };
+        assert_eq!(name(Theme::Dark, Some(ocean)), Some("base16-ocean.dark"));
+        assert_eq!(name(Theme::Dark, None), Some("Solarized (dark)"));
+        assert_eq!(name(Theme::Light, None), Some("InspiredGitHub"));
+
+        let themes = theme_set(&[("no-background", None)]);
+        assert_eq!(select_syntect_theme(&themes, Theme::Dark, None), None);
+    }
+}
@@ -1,16 +1,15 @@
-use crossterm::event::{poll, read, Event, KeyCode, KeyModifiers};
-use crossterm::terminal::{self, is_raw_mode_enabled};
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers};
 use log::debug;
-use scopeguard::defer;
+use std::collections::VecDeque;
 use std::env;
 use std::fmt::Debug;
 use std::io::IsTerminal;
-use std::io::{self, Read, Write};
+use std::io::{self, Write};
+use std::sync::OnceLock;
 use std::time::{Duration, Instant};
 use thiserror::Error;
 #[cfg(target_os = "windows")]
 use {
-    std::sync::OnceLock,
     winapi::um::consoleapi::SetConsoleMode,
     winapi::um::handleapi::INVALID_HANDLE_VALUE,
     winapi::um::processenv::GetStdHandle,
@@ -18,14 +17,98 @@ use {
     winapi::um::wincon::{self, ENABLE_VIRTUAL_TERMINAL_PROCESSING},
 };
 
+mod capabilities;
+mod channel;
+mod convert;
+mod diagnose;
+mod emacs;
+#[cfg(feature = "syntect")]
+mod highlight;
+mod query;
+mod reply;
+mod terminal_io;
+#[cfg(not(target_os = "windows"))]
+mod tmux;
+#[cfg(feature = "ratatui")]
+mod tui;
+
+pub use capabilities::{
+    capabilities, probe_capabilities, Capabilities, Passthrough, ReplyTerminator,
+};
+pub use channel::{ByteChannelEventReader, ChannelEventReader};
+pub use diagnose::{diagnose, Detected, Diagnosis, Source, Step};
+#[cfg(feature = "syntect")]
+pub use highlight::{detect_syntect_theme, select_syntect_theme};
+use query::{build_queries, build_query};
+pub use query::{
+    osc_query, query_bytes, query_sequence, ColorSlot, Controls, Query, QueryOptions,
+    QueryTerminator,
+};
+pub use reply::{Parsed, Reply, ReplyParser};
+use terminal_io::with_terminal_io;
+#[cfg(not(target_os = "windows"))]
+pub use terminal_io::TtyIo;
+pub use terminal_io::{MemoryIo, StdioIo, TerminalIo};
+#[cfg(feature = "ratatui")]
+pub use tui::TuiStyles;
+
 /// Terminal
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+#[non_exhaustive]
 pub enum Terminal {
+    /// GNU Screen, including GNU Screen running inside tmux
     Screen,
     Tmux,
+    /// tmux running inside GNU Screen
+    TmuxInScreen,
+    /// Zellij
+    ///
+    /// Zellij doesn't pass queries through, and answers OSC 10/11 by itself.
+    /// The reported background is the default background of Zellij's pane, which is
+    /// the background of the outer terminal unless a Zellij theme overrides it.
+    Zellij,
     XtermCompatible,
     Windows,
     Emacs,
+    /// Visual Studio Code integrated terminal
+    VsCode,
+    /// JetBrains IDE integrated terminal
+    JetBrains,
+    Kitty,
+    WezTerm,
+    Alacritty,
+    Foot,
+    /// VTE based terminal (GNOME Terminal, Tilix, Terminator, ...)
+    Vte,
+    /// Linux virtual console
+    LinuxConsole,
+    Dumb,
+}
+
+impl Terminal {
+    /// Name in snake_case, same as the serialized one
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Terminal::Screen => "screen",
+            Terminal::Tmux => "tmux",
+            Terminal::TmuxInScreen => "tmux_in_screen",
+            Terminal::Zellij => "zellij",
+            Terminal::XtermCompatible => "xterm_compatible",
+            Terminal::Windows => "windows",
+            Terminal::Emacs => "emacs",
+            Terminal::VsCode => "vs_code",
+            Terminal::JetBrains => "jet_brains",
+            Terminal::Kitty => "kitty",
+            Terminal::WezTerm => "wez_term",
+            Terminal::Alacritty => "alacritty",
+            Terminal::Foot => "foot",
+            Terminal::Vte => "vte",
+            Terminal::LinuxConsole => "linux_console",
+            Terminal::Dumb => "dumb",
+        }
+    }
 }
 
 /// 16bit RGB color
@@ -36,25 +119,163 @@ pub struct Rgb {
     pub b: u16,
 }
 
+/// Serialized as `#rrggbb` if all components are 8bit colors expanded to 16bit,
+/// otherwise as `#rrrrggggbbbb`.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Rgb {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let is_8bit = [self.r, self.g, self.b].iter().all(|x| x >> 8 == x & 0xff);
+        let s = if is_8bit {
+            format!("#{:02x}{:02x}{:02x}", self.r >> 8, self.g >> 8, self.b >> 8)
+        } else {
+            format!("#{:04x}{:04x}{:04x}", self.r, self.g, self.b)
+        };
+        serializer.serialize_str(&s)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Rgb {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        parse_hex_color(&s).ok_or_else(|| {
+            serde::de::Error::invalid_value(
+                serde::de::Unexpected::Str(&s),
+                &"#rrggbb or #rrrrggggbbbb",
+            )
+        })
+    }
+}
+
 /// Background theme
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 pub enum Theme {
     Light,
     Dark,
 }
 
+impl Theme {
+    /// `light` or `dark`, same as the serialized one
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Theme::Light => "light",
+            Theme::Dark => "dark",
+        }
+    }
+}
+
+/// Terminal multiplexer which passes queries through to the outer terminal
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Multiplexer {
+    Tmux,
+    Screen,
+}
+
+/// Remote session between the application and the terminal emulator
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Remote {
+    Ssh,
+    /// mosh, which doesn't pass queries through
+    Mosh,
+}
+
+impl Remote {
+    /// `ssh` or `mosh`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Remote::Ssh => "ssh",
+            Remote::Mosh => "mosh",
+        }
+    }
+}
+
+/// Terminal emulator identification
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TerminalInfo {
+    /// Name reported by XTVERSION (`CSI > q`)
+    pub name: Option<String>,
+    /// Version reported by XTVERSION (`CSI > q`)
+    pub version: Option<String>,
+    /// Parameters of the secondary device attributes reply (`CSI > c`)
+    pub da2_params: Vec<u32>,
+}
+
+/// Result of `detect()`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Detection {
+    pub theme: Theme,
+    /// Background color, unless the theme is reported by the terminal
+    pub rgb: Option<Rgb>,
+    /// Detection strategy which determined the theme
+    pub source: Source,
+    /// Time spent by detection
+    pub elapsed: Duration,
+}
+
+/// Statistics of terminal latency over repeated probes
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct LatencyStats {
+    pub min: Duration,
+    pub median: Duration,
+    /// 95th percentile
+    pub p95: Duration,
+    pub max: Duration,
+}
+
+/// Standard stream
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Stream {
+    Stdin,
+    Stdout,
+    Stderr,
+    /// The device of `TerminalIo`
+    TerminalIo,
+}
+
+impl std::fmt::Display for Stream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Stream::Stdin => write!(f, "stdin"),
+            Stream::Stdout => write!(f, "stdout"),
+            Stream::Stderr => write!(f, "stderr"),
+            Stream::TerminalIo => write!(f, "terminal I/O"),
+        }
+    }
+}
+
 /// Error
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum Error {
-    #[error("io error")]
+    #[error("io error: {source}")]
     Io {
         #[from]
         source: io::Error,
     },
-    #[error("parse error")]
+    #[error("parse error: {0}")]
     Parse(String),
     #[error("unsupported")]
     Unsupported,
+    #[error("no complete reply within {elapsed:.2?} (partial response: {partial_response:?})")]
+    Timeout {
+        elapsed: Duration,
+        partial_response: String,
+    },
+    #[error("{stream} is not a terminal")]
+    NotATerminal { stream: Stream },
+    #[error("dumb terminal")]
+    DumbTerminal,
+    #[error("terminal {} doesn't support the query", .0.as_str())]
+    UnsupportedTerminal(Terminal),
+    #[error("malformed reply: {raw:?}")]
+    MalformedReply { raw: String },
+    #[error("failed to enable raw mode: {source}")]
+    RawModeFailed { source: io::Error },
+    #[error("mosh doesn't pass queries through to the terminal")]
+    MoshSession,
 }
 
 /// A trait to allow mocking of the event reader for testing purposes.
@@ -72,10 +293,14 @@ pub trait EventReader {
     ///
     /// This function will bubble up any i/o or `crossterm` errors encountered.
     fn poll(&self, timeout: Duration) -> Result<bool, Error>;
+    /// Give back an event which was read but is not a part of the reply.
+    ///
+    /// This is called by `rgb_with()` and `theme_with()`. The default drops the event.
+    fn unconsumed(&self, _event: Event) {}
 }
 
 /// A struct to implement real-world use of the event reader, as opposed to use in testing.
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct CrosstermEventReader;
 
 impl EventReader for CrosstermEventReader {
@@ -88,27 +313,256 @@ impl EventReader for CrosstermEventReader {
     }
 }
 
+/// An event reader which reads raw bytes from stdin.
+///
+/// `crossterm` swallows CSI replies such as `CSI ? 997 ; 1 n`, so this reader maps
+/// each byte to a key event in the same way as `crossterm` does for plain characters,
+/// without interpreting any escape sequence.
+/// Bytes outside ASCII are mapped to the corresponding Latin-1 character.
+#[cfg(not(target_os = "windows"))]
+#[derive(Debug)]
+pub(crate) struct TtyEventReader {
+    fd: libc::c_int,
+    pending: std::cell::Cell<Option<u8>>,
+}
+
+#[cfg(not(target_os = "windows"))]
+impl Default for TtyEventReader {
+    fn default() -> Self {
+        TtyEventReader::with_fd(libc::STDIN_FILENO)
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+impl TtyEventReader {
+    /// Read from `fd` instead of stdin.
+    pub(crate) fn with_fd(fd: libc::c_int) -> Self {
+        TtyEventReader {
+            fd,
+            pending: std::cell::Cell::new(None),
+        }
+    }
+
+    fn read_byte(&self) -> Result<u8, Error> {
+        if let Some(byte) = self.pending.take() {
+            return Ok(byte);
+        }
+        let mut buf = [0u8; 1];
+        // Read directly from the file descriptor because `io::Stdin` is buffered,
+        // and buffered bytes would not be reported by `poll`.
+        let ret = unsafe { libc::read(self.fd, buf.as_mut_ptr().cast(), 1) };
+        match ret {
+            1 => Ok(buf[0]),
+            0 => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            _ => Err(io::Error::last_os_error().into()),
+        }
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+impl EventReader for TtyEventReader {
+    fn read_event(&self) -> Result<Event, Error> {
+        let byte = self.read_byte()?;
+        if byte != 0x1b {
+            return Ok(Event::Key(byte_to_key_event(byte)));
+        }
+
+        // ESC followed by another character is reported as ALT + character
+        if !self.poll(Duration::from_millis(10))? {
+            return Ok(Event::Key(KeyCode::Esc.into()));
+        }
+        let next = self.read_byte()?;
+        if next == 0x1b {
+            self.pending.set(Some(next));
+            return Ok(Event::Key(KeyCode::Esc.into()));
+        }
+        let mut key_event = byte_to_key_event(next);
+        key_event.modifiers |= KeyModifiers::ALT;
+        Ok(Event::Key(key_event))
+    }
+
+    fn poll(&self, timeout: Duration) -> Result<bool, Error> {
+        if self.pending.get().is_some() {
+            return Ok(true);
+        }
+        let mut fds = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        let ret = unsafe { libc::poll(&mut fds, 1, timeout) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(false);
+            }
+            return Err(err.into());
+        }
+        Ok(ret > 0 && (fds.revents & libc::POLLIN) != 0)
+    }
+}
+
+/// The event reader used to read replies to CSI queries.
+#[cfg(not(target_os = "windows"))]
+type DefaultEventReader = TtyEventReader;
+
+/// The event reader used to read replies to CSI queries.
+///
+/// Windows doesn't interpret replies, so `crossterm` reports them as plain characters.
+#[cfg(target_os = "windows")]
+type DefaultEventReader = CrosstermEventReader;
+
+/// Terminal I/O through stdio, reading replies by `DefaultEventReader`.
+fn default_io() -> StdioIo<DefaultEventReader> {
+    StdioIo::new(DefaultEventReader::default())
+}
+
+// Same mapping as `crossterm` uses for a single byte of input
+fn byte_to_key_event(byte: u8) -> KeyEvent {
+    match byte {
+        b'\r' => KeyCode::Enter.into(),
+        b'\t' => KeyCode::Tab.into(),
+        0x7f => KeyCode::Backspace.into(),
+        0x00 => KeyEvent::new(KeyCode::Char(' '), KeyModifiers::CONTROL),
+        c @ 0x01..=0x1a => KeyEvent::new(
+            KeyCode::Char((c - 0x01 + b'a') as char),
+            KeyModifiers::CONTROL,
+        ),
+        c @ 0x1c..=0x1f => KeyEvent::new(
+            KeyCode::Char((c - 0x1c + b'4') as char),
+            KeyModifiers::CONTROL,
+        ),
+        c if c.is_ascii_uppercase() => KeyEvent::new(KeyCode::Char(c as char), KeyModifiers::SHIFT),
+        c => KeyEvent::new(KeyCode::Char(char::from(c)), KeyModifiers::NONE),
+    }
+}
+
+/// Pop the key event of the first bytes in `pending`.
+///
+/// ESC followed by another byte in `pending` is reported as ALT + character.
+fn pop_key_event(pending: &mut VecDeque<u8>) -> Option<Event> {
+    let byte = pending.pop_front()?;
+    if byte != 0x1b {
+        return Some(Event::Key(byte_to_key_event(byte)));
+    }
+    match pending.front() {
+        Some(&next) if next != 0x1b => {
+            pending.pop_front();
+            let mut key_event = byte_to_key_event(next);
+            key_event.modifiers |= KeyModifiers::ALT;
+            Some(Event::Key(key_event))
+        }
+        _ => Some(Event::Key(KeyCode::Esc.into())),
+    }
+}
+
+// Reverse of `byte_to_key_event`, to reconstitute the reply sent by the terminal
+fn push_key_event(response: &mut String, key_event: &KeyEvent) {
+    if key_event.modifiers.contains(KeyModifiers::ALT) {
+        response.push('\x1b');
+    }
+    match key_event.code {
+        KeyCode::Char(c) if key_event.modifiers.contains(KeyModifiers::CONTROL) => match c {
+            'a'..='z' => response.push((c as u8 - b'a' + 0x01) as char),
+            '4'..='7' => response.push((c as u8 - b'4' + 0x1c) as char),
+            ' ' => response.push('\0'),
+            _ => response.push(c),
+        },
+        KeyCode::Char(c) => response.push(c),
+        KeyCode::Esc => response.push('\x1b'),
+        KeyCode::Enter => response.push('\r'),
+        KeyCode::Tab => response.push('\t'),
+        KeyCode::Backspace => response.push('\x7f'),
+        _ => debug!("ignoring {key_event:?}\r"),
+    }
+}
+
 /// get detected terminal
 #[cfg(not(target_os = "windows"))]
 pub fn terminal() -> Terminal {
-    if env::var("INSIDE_EMACS").is_ok() {
+    terminal_from_env(|key| env::var(key).ok(), || ancestors().to_vec())
+}
+
+/// Detect terminal from environment variables looked up by `var`.
+///
+/// Multiplexers are checked before terminal emulators because environment variables of
+/// the outer terminal emulator are inherited by the multiplexer.
+/// `ancestors` gets the pids and the command names of the ancestors, nearest first.
+/// It is called only if several multiplexers are found.
+#[cfg(not(target_os = "windows"))]
+fn terminal_from_env<F, G>(var: F, ancestors: G) -> Terminal
+where
+    F: Fn(&str) -> Option<String>,
+    G: Fn() -> Vec<(u32, String)>,
+{
+    if var("INSIDE_EMACS").is_some() {
         return Terminal::Emacs;
     }
 
-    if env::var("TMUX").is_ok() || env::var("TERM").is_ok_and(|x| x.starts_with("tmux-")) {
-        Terminal::Tmux
-    } else {
-        let is_screen = if let Ok(term) = env::var("TERM") {
-            term.starts_with("screen")
-        } else {
-            false
-        };
-        if is_screen {
-            Terminal::Screen
-        } else {
-            Terminal::XtermCompatible
+    let term = var("TERM").unwrap_or_default();
+    if term == "dumb" {
+        return Terminal::Dumb;
+    }
+
+    let is_zellij = var("ZELLIJ").is_some() || var("ZELLIJ_SESSION_NAME").is_some();
+    let is_tmux = var("TMUX").is_some() || term.starts_with("tmux");
+    // tmux inside Zellij is handled as tmux, because queries reach tmux first
+    if is_zellij && (!is_tmux || is_zellij_nearer(&var, &ancestors)) {
+        return Terminal::Zellij;
+    }
+
+    if is_tmux {
+        if var("STY").is_none() {
+            return Terminal::Tmux;
         }
+        // A query wrapped for tmux can't pass through GNU Screen, so GNU Screen inside tmux
+        // is handled as GNU Screen alone.
+        if is_tmux_nearer(&var, &ancestors) {
+            return Terminal::TmuxInScreen;
+        }
+        return Terminal::Screen;
+    }
+
+    if term.starts_with("screen") {
+        return Terminal::Screen;
+    }
+
+    if term == "linux" {
+        return Terminal::LinuxConsole;
+    }
+
+    match var("TERM_PROGRAM").as_deref() {
+        Some("vscode") => return Terminal::VsCode,
+        Some("WezTerm") => return Terminal::WezTerm,
+        _ => (),
+    }
+
+    if var("TERMINAL_EMULATOR").is_some_and(|x| x.starts_with("JetBrains")) {
+        return Terminal::JetBrains;
+    }
+
+    if var("KITTY_WINDOW_ID").is_some() || term == "xterm-kitty" {
+        return Terminal::Kitty;
+    }
+
+    if var("WEZTERM_PANE").is_some() {
+        return Terminal::WezTerm;
+    }
+
+    if var("ALACRITTY_WINDOW_ID").is_some() || term == "alacritty" {
+        return Terminal::Alacritty;
+    }
+
+    if term == "foot" || term.starts_with("foot-") {
+        return Terminal::Foot;
+    }
+
+    if var("VTE_VERSION").is_some() {
+        return Terminal::Vte;
     }
+
+    Terminal::XtermCompatible
 }
 
 /// get detected terminal
@@ -119,7 +573,7 @@ pub fn terminal() -> Terminal {
     // Windows color schemes.
     if let Ok(term_program) = env::var("TERM_PROGRAM") {
         if term_program == "vscode" {
-            return Terminal::XtermCompatible;
+            return Terminal::VsCode;
         }
     }
 
@@ -146,15 +600,112 @@ pub fn terminal() -> Terminal {
     }
 }
 
-/// get background color by `RGB`
+/// Check whether tmux is nearer to the application than GNU Screen, when both are found.
+///
+/// Environment variables of the outer one are inherited by the inner one, so `TERM` set by
+/// the inner one is checked first. Both may set `TERM=screen`, so the servers identified by
+/// `TMUX` and `STY` are looked for in the ancestors of the application in that case.
+#[cfg(not(target_os = "windows"))]
+fn is_tmux_nearer<F, G>(var: F, ancestors: G) -> bool
+where
+    F: Fn(&str) -> Option<String>,
+    G: FnOnce() -> Vec<(u32, String)>,
+{
+    let term = var("TERM").unwrap_or_default();
+    if term.starts_with("tmux") {
+        return true;
+    }
+    // GNU Screen sets `screen.` followed by `TERM` of the outer terminal
+    if term.starts_with("screen.") {
+        return false;
+    }
+
+    // `STY` is `pid.tty.host`
+    let tmux_pid = tmux_pid(&var);
+    let screen_pid = var("STY").and_then(|x| x.split('.').next()?.parse::<u32>().ok());
+    ancestors()
+        .into_iter()
+        .find_map(|(pid, _)| {
+            if Some(pid) == tmux_pid {
+                Some(true)
+            } else if Some(pid) == screen_pid {
+                Some(false)
+            } else {
+                None
+            }
+        })
+        .unwrap_or(true)
+}
+
+/// Check whether Zellij is nearer to the application than tmux, when both are found.
+///
+/// Zellij keeps `TERM` set by tmux, so the tmux server identified by `TMUX` and the Zellij
+/// server are looked for in the ancestors of the application. tmux is assumed if neither is
+/// found, as `is_tmux_nearer()` does.
+#[cfg(not(target_os = "windows"))]
+fn is_zellij_nearer<F, G>(var: F, ancestors: G) -> bool
+where
+    F: Fn(&str) -> Option<String>,
+    G: FnOnce() -> Vec<(u32, String)>,
+{
+    let tmux_pid = tmux_pid(&var);
+    ancestors()
+        .into_iter()
+        .find_map(|(pid, comm)| {
+            let name = comm.rsplit('/').next().unwrap_or_default();
+            if Some(pid) == tmux_pid || name.starts_with("tmux") {
+                Some(false)
+            } else if name.starts_with("zellij") {
+                Some(true)
+            } else {
+                None
+            }
+        })
+        .unwrap_or(false)
+}
+
+/// Get the pid of the tmux server from `TMUX`, which is `socket,pid,session`.
 #[cfg(not(target_os = "windows"))]
+fn tmux_pid<F: Fn(&str) -> Option<String>>(var: F) -> Option<u32> {
+    var("TMUX").and_then(|x| x.split(',').nth(1)?.parse().ok())
+}
+
+/// get background color by `RGB`
+///
+/// Over SSH, `timeout` is extended to cover the round trip measured by `latency()`.
+/// If stdin or stderr is redirected, only the fallbacks without queries such as `COLORFGBG` are tried.
 pub fn rgb(timeout: Duration) -> Result<Rgb, Error> {
+    preflight_fallback()?;
+
     let term = terminal();
+    let timeout = remote_timeout(term, timeout);
+    rgb_of(term, timeout).map(|(rgb, _)| rgb)
+}
+
+#[cfg(not(target_os = "windows"))]
+fn rgb_of(term: Terminal, timeout: Duration) -> Result<(Rgb, Source), Error> {
+    let rgb = match term {
+        // Passthrough is disabled by default since tmux 3.3, and tmux answers by itself,
+        // even under mosh
+        Terminal::Tmux | Terminal::TmuxInScreen if !tmux::allows_passthrough() => {
+            tmux::from_tmux(timeout)
+        }
+        Terminal::Emacs => emacs::from_emacs(timeout),
+        // Zellij answers by itself
+        Terminal::Zellij => from_zellij(&default_io(), timeout).map(|rgb| (rgb, Source::Osc11)),
+        _ if Capabilities::from(term).osc11 => check_remote(term)
+            .and_then(|_| from_xterm(&StdioIo::new(CrosstermEventReader), term, timeout))
+            .map(|rgb| (rgb, Source::Osc11)),
+        _ => Err(Error::UnsupportedTerminal(term)),
+    };
     let rgb = match term {
-        Terminal::Emacs => Err(Error::Unsupported),
-        _ => from_xterm(term, timeout),
+        // The outer terminal may not answer through passthrough
+        Terminal::Tmux | Terminal::TmuxInScreen if tmux::allows_passthrough() => {
+            rgb.or_else(|e| tmux::from_tmux(timeout).map_err(|_| e))
+        }
+        _ => rgb,
     };
-    let fallback = from_env_colorfgbg();
+    let fallback = from_env_colorfgbg().map(|rgb| (rgb, Source::Colorfgbg));
     if rgb.is_ok() {
         rgb
     } else if fallback.is_ok() {
@@ -164,16 +715,17 @@ pub fn rgb(timeout: Duration) -> Result<Rgb, Error> {
     }
 }
 
-/// get background color by `RGB`
 #[cfg(target_os = "windows")]
-pub fn rgb(timeout: Duration) -> Result<Rgb, Error> {
-    let term = terminal();
+fn rgb_of(term: Terminal, timeout: Duration) -> Result<(Rgb, Source), Error> {
     let rgb = match term {
-        Terminal::Emacs => Err(Error::Unsupported),
-        Terminal::XtermCompatible => from_xterm(term, timeout),
-        _ => from_winapi(),
+        Terminal::Emacs => emacs::from_emacs(timeout),
+        Terminal::XtermCompatible | Terminal::VsCode => {
+            from_xterm(&StdioIo::new(CrosstermEventReader), term, timeout)
+                .map(|rgb| (rgb, Source::Osc11))
+        }
+        _ => from_winapi().map(|rgb| (rgb, Source::WinApi)),
     };
-    let fallback = from_env_colorfgbg();
+    let fallback = from_env_colorfgbg().map(|rgb| (rgb, Source::Colorfgbg));
     debug!("rgb={rgb:?}, fallback={fallback:?}\r");
     if rgb.is_ok() {
         rgb
@@ -184,42 +736,539 @@ pub fn rgb(timeout: Duration) -> Result<Rgb, Error> {
     }
 }
 
+/// get background color by `RGB` through an event reader and a writer owned by the caller
+///
+/// This is for applications which already read the terminal input, e.g. a running TUI.
+/// The query is written to `writer`, and the reply is read from `event_reader`, which is
+/// typically `ChannelEventReader` or `ByteChannelEventReader` fed by the event loop of the
+/// application.
+/// Raw mode is neither enabled nor restored, and no input is discarded after the reply.
+/// Events received while waiting for the reply which are not a part of it are given back by
+/// `EventReader::unconsumed()`, and can be taken by `take_unconsumed()` of the channel readers.
+pub fn rgb_with<R, W>(event_reader: &R, writer: &mut W, timeout: Duration) -> Result<Rgb, Error>
+where
+    R: EventReader + Debug,
+    W: Write + Debug,
+{
+    let term = terminal();
+    if !Capabilities::from(term).osc11 {
+        return Err(Error::UnsupportedTerminal(term));
+    }
+    check_remote(term)?;
+    query_background_with(&query_layers(term), timeout, event_reader, writer)
+}
+
+/// get background color by `Theme` through an event reader and a writer owned by the caller
+///
+/// See `rgb_with()` for the requirements.
+/// Unlike `theme()`, the color scheme (`CSI ? 996 n`) is not queried, and the theme is always
+/// computed from the background color.
+pub fn theme_with<R, W>(event_reader: &R, writer: &mut W, timeout: Duration) -> Result<Theme, Error>
+where
+    R: EventReader + Debug,
+    W: Write + Debug,
+{
+    rgb_with(event_reader, writer, timeout).map(rgb_to_theme)
+}
+
+/// get background color by `RGB` through `io`
+///
+/// Raw mode is toggled and excess input is discarded through `io` in the same way as `rgb()`,
+/// but no fallback such as `COLORFGBG` is tried.
+pub fn rgb_io<T: TerminalIo + Debug>(io: &T, timeout: Duration) -> Result<Rgb, Error> {
+    rgb_io_of(io, terminal(), timeout)
+}
+
+fn rgb_io_of<T: TerminalIo + Debug>(
+    io: &T,
+    term: Terminal,
+    timeout: Duration,
+) -> Result<Rgb, Error> {
+    if !Capabilities::from(term).osc11 {
+        return Err(Error::UnsupportedTerminal(term));
+    }
+    check_remote(term)?;
+    if term == Terminal::Zellij {
+        from_zellij(io, timeout)
+    } else {
+        from_xterm(io, term, timeout)
+    }
+}
+
+/// get background color by `Theme` through `io`
+///
+/// See `detect_io()` for the detection strategy.
+pub fn theme_io<T: TerminalIo + Debug>(io: &T, timeout: Duration) -> Result<Theme, Error> {
+    detect_io(io, timeout).map(|detection| detection.theme)
+}
+
+/// get background color by `Theme` through `io` with how it was detected
+///
+/// The color scheme reported by the terminal is preferred and `timeout` is shared as `detect()`
+/// does, but no fallback such as `COLORFGBG` is tried.
+pub fn detect_io<T: TerminalIo + Debug>(io: &T, timeout: Duration) -> Result<Detection, Error> {
+    detect_io_of(io, terminal(), timeout)
+}
+
+fn detect_io_of<T: TerminalIo + Debug>(
+    io: &T,
+    term: Terminal,
+    timeout: Duration,
+) -> Result<Detection, Error> {
+    let start_time = Instant::now();
+
+    let caps = Capabilities::from(term);
+    let mut timeout = timeout;
+    if caps.color_scheme && caps.da1 {
+        let scheme_start_time = Instant::now();
+        let scheme = from_xterm_color_scheme(io, term, timeout);
+        debug!("scheme={scheme:?}\r");
+        match scheme {
+            Ok(theme) => {
+                return Ok(Detection {
+                    theme,
+                    rgb: None,
+                    source: Source::ColorScheme,
+                    elapsed: start_time.elapsed(),
+                })
+            }
+            Err(e) => {
+                timeout = timeout.saturating_sub(scheme_start_time.elapsed());
+                if timeout.is_zero() {
+                    return Err(with_elapsed(e, start_time));
+                }
+            }
+        }
+    }
+
+    let rgb = rgb_io_of(io, term, timeout)?;
+
+    Ok(Detection {
+        theme: rgb_to_theme(rgb),
+        rgb: Some(rgb),
+        source: Source::Osc11,
+        elapsed: start_time.elapsed(),
+    })
+}
+
+/// get terminal latency through `io`
+pub fn latency_io<T: TerminalIo + Debug>(io: &T, timeout: Duration) -> Result<Duration, Error> {
+    let term = terminal();
+    if Capabilities::from(term).dsr {
+        xterm_latency(io, &query_layers(term), timeout)
+    } else {
+        Ok(Duration::from_millis(0))
+    }
+}
+
+/// get foreground color by `RGB`
+pub fn fg(timeout: Duration) -> Result<Rgb, Error> {
+    preflight()?;
+
+    let term = terminal();
+    if !Capabilities::from(term).osc10 {
+        return Err(Error::UnsupportedTerminal(term));
+    }
+    check_remote(term)?;
+    let timeout = remote_timeout(term, timeout);
+    let mut rgb = from_osc_colors(&default_io(), term, &[ColorSlot::Foreground], timeout)?;
+    Ok(rgb.remove(0))
+}
+
+/// get palette colors of `indices` by `RGB`
+///
+/// All colors are queried in one raw mode session.
+pub fn palette(indices: &[u8], timeout: Duration) -> Result<Vec<Rgb>, Error> {
+    preflight()?;
+
+    let term = terminal();
+    if !Capabilities::from(term).osc4 {
+        return Err(Error::UnsupportedTerminal(term));
+    }
+    check_remote(term)?;
+    let timeout = remote_timeout(term, timeout);
+    let slots: Vec<_> = indices.iter().copied().map(ColorSlot::Palette).collect();
+    from_osc_colors(&default_io(), term, &slots, timeout)
+}
+
 /// get terminal latency
 #[cfg(not(target_os = "windows"))]
 pub fn latency(timeout: Duration) -> Result<Duration, Error> {
+    preflight()?;
+
     let term = terminal();
-    match term {
-        Terminal::Emacs => Ok(Duration::from_millis(0)),
-        _ => xterm_latency(timeout),
+    if Capabilities::from(term).dsr {
+        xterm_latency(&default_io(), &query_layers(term), timeout)
+    } else {
+        Ok(Duration::from_millis(0))
     }
 }
 
 /// get terminal latency
 #[cfg(target_os = "windows")]
 pub fn latency(timeout: Duration) -> Result<Duration, Error> {
+    preflight()?;
+
     let term = terminal();
     match term {
         Terminal::Emacs => Ok(Duration::from_millis(0)),
-        Terminal::XtermCompatible => xterm_latency(timeout),
+        Terminal::XtermCompatible | Terminal::VsCode => xterm_latency(&default_io(), &[], timeout),
         _ => Ok(Duration::from_millis(0)),
     }
 }
 
-/// get background color by `Theme`
-pub fn theme(timeout: Duration) -> Result<Theme, Error> {
-    let rgb = rgb(timeout)?;
-
-    // ITU-R BT.601
-    let y = rgb.r as f64 * 0.299 + rgb.g as f64 * 0.587 + rgb.b as f64 * 0.114;
+/// get statistics of terminal latency over `samples` probes
+///
+/// All probes are sent in one raw mode session, one after another.
+pub fn latency_stats(samples: usize, timeout: Duration) -> Result<LatencyStats, Error> {
+    preflight()?;
 
-    if y > 32768.0 {
-        Ok(Theme::Light)
+    let term = terminal();
+    if Capabilities::from(term).dsr {
+        xterm_latency_stats(&default_io(), &query_layers(term), samples, timeout)
     } else {
-        Ok(Theme::Dark)
+        Ok(LatencyStats::default())
     }
 }
 
-// Function to enable virtual terminal processing for Windows
+/// get background color by `Theme`
+///
+/// The color scheme reported by the terminal (`CSI ? 996 n`) is preferred.
+/// If the terminal doesn't report it, the theme is computed from the background color.
+pub fn theme(timeout: Duration) -> Result<Theme, Error> {
+    detect(timeout).map(|detection| detection.theme)
+}
+
+/// get background color by `Theme` with how it was detected
+///
+/// The detection strategy is the same as `theme()`.
+/// `timeout` is shared by the color scheme query and the background color query, which is not
+/// sent if the color scheme query uses up `timeout`.
+pub fn detect(timeout: Duration) -> Result<Detection, Error> {
+    let start_time = Instant::now();
+    preflight_fallback()?;
+
+    let term = terminal();
+    let caps = Capabilities::from(term);
+    let mut timeout = remote_timeout(term, timeout);
+    if caps.color_scheme && caps.da1 {
+        let scheme_start_time = Instant::now();
+        let scheme = from_xterm_color_scheme(&default_io(), term, timeout);
+        debug!("scheme={scheme:?}\r");
+        match scheme {
+            Ok(theme) => {
+                return Ok(Detection {
+                    theme,
+                    rgb: None,
+                    source: Source::ColorScheme,
+                    elapsed: start_time.elapsed(),
+                })
+            }
+            Err(e) => {
+                // The background color query shares the timeout, so that an unanswered DA1
+                // doesn't double the wait
+                timeout = timeout.saturating_sub(scheme_start_time.elapsed());
+                // The query is not sent without time to wait for the reply, which would leak
+                // into the input of the application
+                if timeout.is_zero() {
+                    let rgb = from_env_colorfgbg().map_err(|_| with_elapsed(e, start_time))?;
+                    return Ok(Detection {
+                        theme: rgb_to_theme(rgb),
+                        rgb: Some(rgb),
+                        source: Source::Colorfgbg,
+                        elapsed: start_time.elapsed(),
+                    });
+                }
+            }
+        }
+    }
+
+    let (rgb, source) = rgb_of(term, timeout)?;
+
+    Ok(Detection {
+        theme: rgb_to_theme(rgb),
+        rgb: Some(rgb),
+        source,
+        elapsed: start_time.elapsed(),
+    })
+}
+
+/// Report the time since `start_time` by `Error::Timeout`.
+fn with_elapsed(e: Error, start_time: Instant) -> Error {
+    match e {
+        Error::Timeout {
+            partial_response, ..
+        } => Error::Timeout {
+            elapsed: start_time.elapsed(),
+            partial_response,
+        },
+        e => e,
+    }
+}
+
+fn rgb_to_theme(rgb: Rgb) -> Theme {
+    // ITU-R BT.601
+    let y = rgb.r as f64 * 0.299 + rgb.g as f64 * 0.587 + rgb.b as f64 * 0.114;
+
+    if y > 32768.0 {
+        Theme::Light
+    } else {
+        Theme::Dark
+    }
+}
+
+/// get terminal emulator identification by XTVERSION and DA2
+pub fn terminal_info(timeout: Duration) -> Result<TerminalInfo, Error> {
+    preflight()?;
+
+    let term = terminal();
+    if Capabilities::from(term).da1 {
+        from_xterm_info(&default_io(), term, timeout)
+    } else {
+        Err(Error::UnsupportedTerminal(term))
+    }
+}
+
+/// get the remote session between the application and the terminal emulator
+pub fn remote() -> Option<Remote> {
+    remote_from_env(|key| env::var(key).ok(), is_mosh)
+}
+
+/// mosh is started through SSH, so it is looked for only when `SSH_CONNECTION` or `SSH_TTY` is set.
+fn remote_from_env<F, G>(var: F, is_mosh: G) -> Option<Remote>
+where
+    F: Fn(&str) -> Option<String>,
+    G: FnOnce() -> bool,
+{
+    if var("SSH_CONNECTION").is_none() && var("SSH_TTY").is_none() {
+        None
+    } else if is_mosh() {
+        Some(Remote::Mosh)
+    } else {
+        Some(Remote::Ssh)
+    }
+}
+
+/// mosh doesn't set any environment variable, so look for `mosh-server` in the ancestors.
+#[cfg(not(target_os = "windows"))]
+fn is_mosh() -> bool {
+    ancestors()
+        .iter()
+        .any(|(_, comm)| comm.ends_with("mosh-server"))
+}
+
+#[cfg(target_os = "windows")]
+fn is_mosh() -> bool {
+    false
+}
+
+/// Get the pids and the command names of the ancestors of this process, nearest first.
+///
+/// They are read from `/proc` on Linux, and from a single `ps` call elsewhere.
+#[cfg(not(target_os = "windows"))]
+fn ancestors() -> &'static [(u32, String)] {
+    static ANCESTORS: OnceLock<Vec<(u32, String)>> = OnceLock::new();
+    ANCESTORS.get_or_init(|| {
+        let pid = std::process::id();
+        let ancestors = if std::path::Path::new("/proc/self/stat").exists() {
+            walk_ancestors(pid, proc_process)
+        } else {
+            let processes = ps_processes();
+            walk_ancestors(pid, |pid| processes.get(&pid).cloned())
+        };
+        debug!("ancestors={ancestors:?}\r");
+        ancestors
+    })
+}
+
+/// Follow the parents from `pid` by `process`, which gets the parent pid and the command name.
+#[cfg(not(target_os = "windows"))]
+fn walk_ancestors<F>(pid: u32, process: F) -> Vec<(u32, String)>
+where
+    F: Fn(u32) -> Option<(u32, String)>,
+{
+    let mut ancestors = Vec::new();
+    let mut ppid = process(pid).map(|(ppid, _)| ppid);
+    // Limit the depth in case of a cycle by pid reuse
+    while let Some(pid) = ppid.filter(|&pid| pid > 1 && ancestors.len() < 64) {
+        let Some((next, comm)) = process(pid) else {
+            break;
+        };
+        ancestors.push((pid, comm));
+        ppid = Some(next);
+    }
+    ancestors
+}
+
+/// Get the parent pid and the command name of `pid` from `/proc`.
+#[cfg(not(target_os = "windows"))]
+fn proc_process(pid: u32) -> Option<(u32, String)> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // The command name in parentheses may contain spaces, so the fields after it are used
+    let ppid = stat.rsplit_once(')')?.1.split_whitespace().nth(1)?;
+    let comm = std::fs::read_to_string(format!("/proc/{pid}/comm")).ok()?;
+    Some((ppid.parse().ok()?, comm.trim_end().to_string()))
+}
+
+/// Get the parent pids and the command names of all processes by `ps`.
+#[cfg(not(target_os = "windows"))]
+fn ps_processes() -> std::collections::HashMap<u32, (u32, String)> {
+    let output = std::process::Command::new("ps")
+        .args(["-A", "-o", "pid=", "-o", "ppid=", "-o", "comm="])
+        .output();
+    match output {
+        Ok(output) if output.status.success() => {
+            parse_ps_processes(&String::from_utf8_lossy(&output.stdout))
+        }
+        _ => Default::default(),
+    }
+}
+
+/// Parse lines of `pid ppid comm`, where `comm` may contain spaces.
+#[cfg(not(target_os = "windows"))]
+fn parse_ps_processes(output: &str) -> std::collections::HashMap<u32, (u32, String)> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (pid, rest) = line.trim_start().split_once(char::is_whitespace)?;
+            let (ppid, comm) = rest.trim_start().split_once(char::is_whitespace)?;
+            Some((
+                pid.parse().ok()?,
+                (ppid.parse().ok()?, comm.trim().to_string()),
+            ))
+        })
+        .collect()
+}
+
+/// Queries other than the ones answered by Zellij itself don't reach the terminal under mosh.
+fn check_remote(term: Terminal) -> Result<(), Error> {
+    if term != Terminal::Zellij && remote() == Some(Remote::Mosh) {
+        Err(Error::MoshSession)
+    } else {
+        Ok(())
+    }
+}
+
+/// Upper bound of the latency probe over SSH, which is waited for only once
+const REMOTE_PROBE_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Timeout per latency, because a reply may need several round trips through multiplexers
+const LATENCY_FACTOR: u32 = 4;
+
+/// Lower bound of `auto_timeout()`
+pub const AUTO_TIMEOUT_MIN: Duration = Duration::from_millis(50);
+
+/// Upper bound of `auto_timeout()`
+pub const AUTO_TIMEOUT_MAX: Duration = Duration::from_secs(1);
+
+/// Upper bound of the latency probe by `auto_timeout()`, beyond which the latency results in
+/// `AUTO_TIMEOUT_MAX` anyway
+const AUTO_PROBE_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// get a timeout suited to the terminal from the measured latency
+///
+/// The round trip of `CSI 5 n` is measured, and a multiple of it is clamped between
+/// `AUTO_TIMEOUT_MIN` and `AUTO_TIMEOUT_MAX`.
+/// If the terminal doesn't answer `CSI 5 n` in time, the recommended timeout of its capabilities
+/// is used.
+pub fn auto_timeout() -> Duration {
+    let term = terminal();
+    let caps = Capabilities::from(term);
+    let fallback = caps.timeout.clamp(AUTO_TIMEOUT_MIN, AUTO_TIMEOUT_MAX);
+    if !caps.dsr || preflight().is_err() {
+        return fallback;
+    }
+    match xterm_latency(&default_io(), &query_layers(term), AUTO_PROBE_TIMEOUT) {
+        Ok(latency) => {
+            let timeout = timeout_from_latency(latency);
+            debug!("latency={latency:?}, auto timeout={timeout:?}\r");
+            timeout
+        }
+        Err(e) => {
+            debug!("latency probe failed: {e:?}\r");
+            fallback
+        }
+    }
+}
+
+fn timeout_from_latency(latency: Duration) -> Duration {
+    (latency * LATENCY_FACTOR).clamp(AUTO_TIMEOUT_MIN, AUTO_TIMEOUT_MAX)
+}
+
+/// Extend `timeout` over SSH by probing the latency of the terminal.
+fn remote_timeout(term: Terminal, timeout: Duration) -> Duration {
+    if remote() != Some(Remote::Ssh) || !Capabilities::from(term).dsr {
+        return timeout;
+    }
+    match remote_latency(term) {
+        Some(latency) => {
+            let timeout = adapt_timeout(timeout, latency);
+            debug!("latency={latency:?}, timeout={timeout:?}\r");
+            timeout
+        }
+        None => timeout,
+    }
+}
+
+/// Probe the latency over SSH only once, and reuse it for later queries.
+///
+/// A failed probe is not retried, so that a silent terminal doesn't delay every query.
+fn remote_latency(term: Terminal) -> Option<Duration> {
+    static REMOTE_LATENCY: OnceLock<Option<Duration>> = OnceLock::new();
+    *REMOTE_LATENCY.get_or_init(|| {
+        match xterm_latency(&default_io(), &query_layers(term), REMOTE_PROBE_TIMEOUT) {
+            Ok(latency) => Some(latency),
+            Err(e) => {
+                debug!("latency probe failed: {e:?}\r");
+                None
+            }
+        }
+    })
+}
+
+fn adapt_timeout(timeout: Duration, latency: Duration) -> Duration {
+    timeout.max(latency * LATENCY_FACTOR)
+}
+
+/// Check whether detection is worth attempting, so that callers don't have to wait for timeout.
+fn preflight() -> Result<(), Error> {
+    preflight_from_env(|key| env::var(key).ok(), non_terminal_stream())
+}
+
+/// Same as `preflight()`, but redirected stdio is allowed because fallbacks without queries
+/// may still work. The query paths check the terminal by themselves.
+fn preflight_fallback() -> Result<(), Error> {
+    preflight_from_env(|key| env::var(key).ok(), None)
+}
+
+/// `TERM=dumb`, `NO_COLOR` and CI runners without `TERM` are reported as `Error::DumbTerminal`.
+/// Redirected stdio is reported as `Error::NotATerminal`.
+///
+/// Inside Emacs, `TERM=dumb` and redirected stdio are allowed because Emacs itself can be asked.
+fn preflight_from_env<F>(var: F, non_terminal_stream: Option<Stream>) -> Result<(), Error>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    if var("NO_COLOR").is_some_and(|x| !x.is_empty()) {
+        return Err(Error::DumbTerminal);
+    }
+    let term = var("TERM");
+    if term.is_none() && var("CI").is_some() {
+        return Err(Error::DumbTerminal);
+    }
+    if var("INSIDE_EMACS").is_some() {
+        return Ok(());
+    }
+    if term.as_deref() == Some("dumb") {
+        return Err(Error::DumbTerminal);
+    }
+    if let Some(stream) = non_terminal_stream {
+        return Err(Error::NotATerminal { stream });
+    }
+    Ok(())
+}
+
+// Function to enable virtual terminal processing for Windows
 #[cfg(target_os = "windows")]
 fn enable_virtual_terminal_processing() -> bool {
     static ENABLE_VT_PROCESSING: OnceLock<bool> = OnceLock::new();
@@ -244,63 +1293,425 @@ fn enable_virtual_terminal_processing() -> bool {
     })
 }
 
-fn from_xterm(term: Terminal, timeout: Duration) -> Result<Rgb, Error> {
-    if !std::io::stdin().is_terminal()
-        || !std::io::stdout().is_terminal()
-        || !std::io::stderr().is_terminal()
-    {
-        // Not a terminal, so don't try to read the current background color.
-        return Err(Error::Unsupported);
+fn from_xterm<T: TerminalIo + Debug>(
+    io: &T,
+    term: Terminal,
+    timeout: Duration,
+) -> Result<Rgb, Error> {
+    from_xterm_through(io, &query_layers(term), timeout)
+}
+
+/// Query the background color through `layers` of terminal multiplexers.
+fn from_xterm_through<T: TerminalIo + Debug>(
+    io: &T,
+    layers: &[Multiplexer],
+    timeout: Duration,
+) -> Result<Rgb, Error> {
+    with_terminal_io(io, |event_reader, writer| {
+        #[cfg(target_os = "windows")]
+        {
+            if !enable_virtual_terminal_processing() {
+                debug!(
+                    "Virtual Terminal Processing could not be enabled. Falling back to default behavior.\r"
+                );
+                return from_winapi();
+            }
+        }
+
+        query_xterm(layers, timeout, event_reader, writer)
+    })
+}
+
+/// Query the background color answered by Zellij itself.
+///
+/// Older Zellij doesn't answer, so DA1 is used to avoid waiting for timeout.
+fn from_zellij<T: TerminalIo + Debug>(io: &T, timeout: Duration) -> Result<Rgb, Error> {
+    with_terminal_io(io, |event_reader, writer| {
+        query_osc_color(&[], ColorSlot::Background, timeout, event_reader, writer)
+    })
+}
+
+/// Query colors of `slots` by `OSC Ps ; ?`.
+fn from_osc_colors<T: TerminalIo + Debug>(
+    io: &T,
+    term: Terminal,
+    slots: &[ColorSlot],
+    timeout: Duration,
+) -> Result<Vec<Rgb>, Error> {
+    let layers = query_layers(term);
+
+    with_terminal_io(io, |event_reader, writer| {
+        slots
+            .iter()
+            .map(|&slot| query_osc_color(&layers, slot, timeout, event_reader, writer))
+            .collect()
+    })
+}
+
+fn from_xterm_color_scheme<T: TerminalIo + Debug>(
+    io: &T,
+    term: Terminal,
+    timeout: Duration,
+) -> Result<Theme, Error> {
+    let layers = query_layers(term);
+
+    with_terminal_io(io, |event_reader, writer| {
+        query_color_scheme(&layers, timeout, event_reader, writer)
+    })
+}
+
+fn from_xterm_info<T: TerminalIo + Debug>(
+    io: &T,
+    term: Terminal,
+    timeout: Duration,
+) -> Result<TerminalInfo, Error> {
+    let layers = query_layers(term);
+
+    with_terminal_io(io, |event_reader, writer| {
+        query_terminal_info(&layers, timeout, event_reader, writer)
+    })
+}
+
+/// Queries are written to stderr and replies are read from stdin, so stdout may be redirected,
+/// e.g. captured by `$(...)` of shell.
+fn non_terminal_stream() -> Option<Stream> {
+    if !io::stdin().is_terminal() {
+        Some(Stream::Stdin)
+    } else if !io::stderr().is_terminal() {
+        Some(Stream::Stderr)
+    } else {
+        None
     }
+}
 
-    let raw_before = is_raw_mode_enabled()?;
+/// get terminal multiplexers between the application and the terminal emulator, nearest first
+pub fn multiplexers() -> Vec<Multiplexer> {
+    multiplexers_of(terminal())
+}
 
-    defer! {
-        let is_raw = match is_raw_mode_enabled() {
-            Ok(val) => val,
-            Err(e) => {
-                debug!("Failed to check raw mode status: {:?}\r", e);
-                return;
+/// get terminal multiplexers which queries are passed through, nearest first
+///
+/// Unlike `multiplexers()`, this is empty under tmux which doesn't allow passthrough,
+/// because tmux answers the queries by itself.
+pub fn query_multiplexers() -> Vec<Multiplexer> {
+    query_layers(terminal())
+}
+
+/// Multiplexers which queries are passed through.
+///
+/// tmux 3.3 or later doesn't pass queries through unless `allow-passthrough` is on,
+/// so queries are not wrapped at all, and tmux answers them by itself.
+fn query_layers(term: Terminal) -> Vec<Multiplexer> {
+    let layers = multiplexers_of(term);
+    #[cfg(not(target_os = "windows"))]
+    if layers.first() == Some(&Multiplexer::Tmux) && !tmux::allows_passthrough() {
+        debug!("tmux doesn't allow passthrough\r");
+        return Vec::new();
+    }
+    layers
+}
+
+fn multiplexers_of(term: Terminal) -> Vec<Multiplexer> {
+    multiplexers_from_env(term, |key| env::var(key).ok(), tmux_client_term)
+}
+
+/// Detect nested multiplexers.
+///
+/// A multiplexer beyond an SSH connection is not visible from environment variables,
+/// so the client attached to tmux is checked when running over SSH.
+/// `client_term` gets `TERM` of the client and the terminal type reported by it.
+///
+/// tmux sets `TERM=screen` by default before tmux 3.5, so `screen` alone doesn't mean
+/// GNU Screen. tmux 3.3 or later reports the terminal type like `tmux 3.4`.
+fn multiplexers_from_env<F, G>(term: Terminal, var: F, client_term: G) -> Vec<Multiplexer>
+where
+    F: Fn(&str) -> Option<String>,
+    G: FnOnce() -> Option<(String, String)>,
+{
+    let mut layers = match term {
+        Terminal::Tmux => vec![Multiplexer::Tmux],
+        Terminal::Screen => vec![Multiplexer::Screen],
+        Terminal::TmuxInScreen => vec![Multiplexer::Tmux, Multiplexer::Screen],
+        _ => return Vec::new(),
+    };
+
+    let is_remote = var("SSH_CONNECTION").is_some() || var("SSH_TTY").is_some();
+    if term == Terminal::Tmux && is_remote {
+        match client_term() {
+            Some((name, termtype)) if name.starts_with("tmux") || termtype.starts_with("tmux") => {
+                layers.push(Multiplexer::Tmux)
             }
-        };
+            // GNU Screen sets `screen.` followed by `TERM` of the outer terminal
+            Some((name, _)) if name.starts_with("screen.") => layers.push(Multiplexer::Screen),
+            _ => (),
+        }
+    }
+    debug!("multiplexers={layers:?}\r");
+    layers
+}
 
-        if is_raw == raw_before {
-            debug!("Raw mode status unchanged from raw={raw_before}.\r");
-        } else if let Err(e) = restore_raw_status(raw_before) {
-            debug!("Failed to restore raw mode: {e:?} to raw={raw_before}\r");
-        } else {
-            debug!("Raw mode restored to previous state (raw={raw_before}).\r");
+/// Format of `display-message` to get the terminal of the client attached to tmux
+const TMUX_CLIENT_FORMAT: &str = "#{client_termname};#{client_termtype}";
+
+/// Get `TERM` of the client attached to tmux and the terminal type reported by it.
+///
+/// The client doesn't change while the application runs, so tmux is asked only once.
+fn tmux_client_term() -> Option<(String, String)> {
+    static CLIENT_TERM: OnceLock<Option<(String, String)>> = OnceLock::new();
+    CLIENT_TERM
+        .get_or_init(|| {
+            let output = std::process::Command::new("tmux")
+                .args(["display-message", "-p", TMUX_CLIENT_FORMAT])
+                .output()
+                .ok()?;
+            if !output.status.success() {
+                return None;
+            }
+            let output = String::from_utf8(output.stdout).ok()?;
+            let (name, termtype) = output.trim().split_once(';')?;
+            debug!("tmux client_termname={name:?}, client_termtype={termtype:?}\r");
+            Some((name.to_string(), termtype.to_string()))
+        })
+        .clone()
+}
+
+/// Wrap `query` so that it is passed through `layers` of terminal multiplexers, nearest first,
+/// to the terminal emulator.
+///
+/// tmux requires ESC in the wrapped query to be doubled at each level.
+/// GNU Screen ends passthrough at the first ST, so queries passed through it should be
+/// terminated by BEL, and a query wrapped for tmux can't be wrapped for GNU Screen again.
+/// So GNU Screen inside tmux is not supported, while tmux inside GNU Screen is.
+/// The wrapping itself always uses 7-bit controls, even if `query` uses 8-bit controls.
+fn wrap_passthrough(layers: &[Multiplexer], query: &[u8]) -> Vec<u8> {
+    layers
+        .iter()
+        .rev()
+        .fold(query.to_vec(), |query, layer| match layer {
+            Multiplexer::Tmux => {
+                let mut ret = b"\x1bPtmux;".to_vec();
+                for byte in query {
+                    if byte == 0x1b {
+                        ret.push(0x1b);
+                    }
+                    ret.push(byte);
+                }
+                ret.extend_from_slice(b"\x1b\\");
+                ret
+            }
+            Multiplexer::Screen => [b"\x1bP", &query[..], b"\x1b\\"].concat(),
+        })
+}
+
+/// Query the preferred color scheme by `CSI ? 996 n`.
+///
+/// The query is followed by DA1 (`CSI c`), which is answered by almost all terminals.
+/// If the DA1 reply arrives without a color scheme reply, the query is unsupported.
+fn query_color_scheme<R, W>(
+    layers: &[Multiplexer],
+    timeout: Duration,
+    event_reader: &R,
+    buffer: &mut W,
+) -> Result<Theme, Error>
+where
+    R: EventReader + Debug,
+    W: Write + Debug,
+{
+    let query = build_queries(
+        layers,
+        &[Query::ColorScheme, Query::DeviceAttributes],
+        QueryOptions::default(),
+    );
+
+    // Send query
+    buffer.write_all(&query)?;
+    buffer.flush()?;
+
+    let response = read_reply(timeout, event_reader, |response| {
+        find_da1_reply(response).is_some()
+    })?;
+
+    parse_color_scheme(&response).ok_or(Error::Unsupported)
+}
+
+/// Query the terminal emulator identification by XTVERSION (`CSI > q`) and DA2 (`CSI > c`).
+///
+/// The queries are followed by DA1 (`CSI c`) to detect the end of replies.
+fn query_terminal_info<R, W>(
+    layers: &[Multiplexer],
+    timeout: Duration,
+    event_reader: &R,
+    buffer: &mut W,
+) -> Result<TerminalInfo, Error>
+where
+    R: EventReader + Debug,
+    W: Write + Debug,
+{
+    let query = build_queries(
+        layers,
+        &[
+            Query::Version,
+            Query::SecondaryDeviceAttributes,
+            Query::DeviceAttributes,
+        ],
+        QueryOptions::default(),
+    );
+
+    // Send query
+    buffer.write_all(&query)?;
+    buffer.flush()?;
+
+    let response = read_reply(timeout, event_reader, |response| {
+        find_da1_reply(response).is_some()
+    })?;
+
+    let (name, version) = match parse_xtversion(&response) {
+        Some((name, version)) => (Some(name), version),
+        None => (None, None),
+    };
+    let da2_params: Vec<u32> = find_csi_reply(&response, "\x1b[>", 'c')
+        .map(|params| {
+            params
+                .split(';')
+                .filter_map(|param| param.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    if name.is_none() && da2_params.is_empty() {
+        return Err(Error::Unsupported);
+    }
+
+    Ok(TerminalInfo {
+        name,
+        version,
+        da2_params,
+    })
+}
+
+/// Parse the XTVERSION reply (`DCS > | text ST`) into name and version.
+///
+/// The text is formatted as `name(version)` by xterm, kitty and foot,
+/// and as `name version` by tmux and WezTerm.
+fn parse_xtversion(response: &str) -> Option<(String, Option<String>)> {
+    let start = response.find("\x1bP>|")? + 4;
+    let rest = &response[start..];
+    let end = rest.find(['\x1b', '\x07'])?;
+    let text = rest[..end].trim();
+
+    if let Some((name, version)) = text.split_once('(') {
+        let version = version.trim_end_matches(')');
+        Some((name.trim().to_string(), Some(version.to_string())))
+    } else if let Some((name, version)) = text.split_once(' ') {
+        Some((name.to_string(), Some(version.trim().to_string())))
+    } else {
+        Some((text.to_string(), None))
+    }
+}
+
+/// Read events until `is_complete` returns true for the reconstituted reply.
+fn read_reply<R, F>(timeout: Duration, event_reader: &R, is_complete: F) -> Result<String, Error>
+where
+    R: EventReader,
+    F: Fn(&str) -> bool,
+{
+    let mut response = String::new();
+    let start_time = Instant::now();
+
+    loop {
+        if start_time.elapsed() > timeout {
+            debug!("After timeout, found response={response:?}\r");
+            return Err(Error::Timeout {
+                elapsed: start_time.elapsed(),
+                partial_response: response,
+            });
         }
 
-        if let Err(e) = clear_stdin() {
-            debug!("Failed to clear stdin: {e:?}\r");
-        } else {
-            debug!("Cleared any excess from stdin.\r");
+        if event_reader.poll(poll_interval(timeout, start_time))? {
+            if let Event::Key(key_event) = event_reader.read_event()? {
+                push_key_event(&mut response, &key_event);
+                if is_complete(&response) {
+                    debug!("Elapsed time: {:.2?}\r", start_time.elapsed());
+                    return Ok(response);
+                }
+            }
         }
     }
+}
 
-    if !raw_before {
-        terminal::enable_raw_mode()?;
-    }
+/// Interval of polling for a reply, which doesn't exceed the rest of `timeout`.
+fn poll_interval(timeout: Duration, start_time: Instant) -> Duration {
+    Duration::from_millis(100).min(timeout.saturating_sub(start_time.elapsed()))
+}
 
-    #[cfg(target_os = "windows")]
-    {
-        if !enable_virtual_terminal_processing() {
-            debug!(
-                "Virtual Terminal Processing could not be enabled. Falling back to default behavior.\r"
-            );
-            return from_winapi();
+/// Find the parameters of a DA1 reply (`CSI ? Ps ; ... c`).
+fn find_da1_reply(response: &str) -> Option<&str> {
+    find_csi_reply(response, "\x1b[?", 'c')
+}
+
+/// Find the parameters of a CSI reply starting with `intro` and ending with `final_char`.
+fn find_csi_reply<'a>(response: &'a str, intro: &str, final_char: char) -> Option<&'a str> {
+    let mut rest = response;
+    while let Some(pos) = rest.find(intro) {
+        rest = &rest[pos + intro.len()..];
+        let end = rest.find(|c: char| !c.is_ascii_digit() && c != ';')?;
+        if rest[end..].starts_with(final_char) {
+            return Some(&rest[..end]);
         }
     }
+    None
+}
+
+/// Query the color of `slot` by `OSC Ps ; ?`.
+///
+/// The query is terminated by BEL, which is accepted by all terminals, and followed by
+/// DA1 to detect an unanswered query without waiting for timeout.
+fn query_osc_color<R, W>(
+    layers: &[Multiplexer],
+    slot: ColorSlot,
+    timeout: Duration,
+    event_reader: &R,
+    buffer: &mut W,
+) -> Result<Rgb, Error>
+where
+    R: EventReader + Debug,
+    W: Write + Debug,
+{
+    let query = build_queries(
+        layers,
+        &[slot.into(), Query::DeviceAttributes],
+        QueryOptions::default(),
+    );
+
+    // Send query
+    buffer.write_all(&query)?;
+    buffer.flush()?;
 
-    let event_reader = CrosstermEventReader;
-    let mut stderr = io::stderr();
+    let response = read_reply(timeout, event_reader, |response| {
+        find_da1_reply(response).is_some()
+    })?;
 
-    query_xterm(term, timeout, &event_reader, &mut stderr)
+    let start = response
+        .find(&format!("\x1b]{};", slot.osc()))
+        .ok_or(Error::Unsupported)?;
+    let rgb_slice = decode_unterminated(&response[start..])?;
+    let (r, g, b) = extract_rgb(rgb_slice)?;
+    Ok(Rgb { r, g, b })
+}
+
+/// Parse the color scheme reply (`CSI ? 997 ; 1 n` is dark, `CSI ? 997 ; 2 n` is light).
+fn parse_color_scheme(response: &str) -> Option<Theme> {
+    if response.contains("\x1b[?997;1n") {
+        Some(Theme::Dark)
+    } else if response.contains("\x1b[?997;2n") {
+        Some(Theme::Light)
+    } else {
+        None
+    }
 }
 
 fn query_xterm<R, W>(
-    term: Terminal,
+    layers: &[Multiplexer],
     timeout: Duration,
     event_reader: &R,
     buffer: &mut W,
@@ -310,14 +1721,10 @@ where
     W: Write + Debug,
 {
     // Query by XTerm control sequence
-    let query = match term {
-        Terminal::Tmux => "\x1bPtmux;\x1b\x1b]11;?\x07\x1b\\",
-        Terminal::Screen => "\x1bP\x1b]11;?\x07\x1b\\",
-        _ => "\x1b]11;?\x1b\\",
-    };
+    let query = build_query(layers, Query::Background, QueryOptions::for_layers(layers));
 
     // Send query
-    write!(buffer, "{query}")?;
+    buffer.write_all(&query)?;
     buffer.flush()?;
 
     let mut response = String::new();
@@ -333,13 +1740,16 @@ where
                 return parse_response(rgb_slice, start_time);
             }
             debug!("Failed to capture response\r");
-            return Err(io::Error::new(io::ErrorKind::TimedOut, "timeout 1").into());
+            return Err(Error::Timeout {
+                elapsed: start_time.elapsed(),
+                partial_response: response,
+            });
         }
 
         // Replaced expensive async_std with blocking loop. Terminal normally responds
         // fast or not at all, and in the latter case we still have the timeout on the
         // main loop.
-        if event_reader.poll(Duration::from_millis(100))? {
+        if event_reader.poll(poll_interval(timeout, start_time))? {
             // Read the next event.
             // Replaced stdin read that was consuming legit user input in Windows
             // with non-blocking crossterm read event.
@@ -361,20 +1771,103 @@ where
                         debug!("pushing {c}\r");
                         response.push(c);
                     }
-                    _ => {
-                        // Ignore other keys
-                        debug!("ignoring {key_event:?}\r");
+                    _ => {
+                        // Ignore other keys
+                        debug!("ignoring {key_event:?}\r");
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Query the background color through an event reader fed by the caller.
+///
+/// Key events are fed to `ReplyParser` as bytes. Events which turn out not to be a part of
+/// the reply are given back to `event_reader` in the order of arrival, as soon as they are known.
+fn query_background_with<R, W>(
+    layers: &[Multiplexer],
+    timeout: Duration,
+    event_reader: &R,
+    buffer: &mut W,
+) -> Result<Rgb, Error>
+where
+    R: EventReader + Debug,
+    W: Write + Debug,
+{
+    let query = build_query(layers, Query::Background, QueryOptions::for_layers(layers));
+
+    // Send query
+    buffer.write_all(&query)?;
+    buffer.flush()?;
+
+    let mut parser = ReplyParser::new();
+    // Events held as a possible part of the reply, with their length in bytes
+    let mut held: VecDeque<(Event, usize)> = VecDeque::new();
+    let start_time = Instant::now();
+
+    let ret = loop {
+        if start_time.elapsed() > timeout {
+            break Err(Error::Timeout {
+                elapsed: start_time.elapsed(),
+                partial_response: String::from_utf8_lossy(&parser.flush()).into_owned(),
+            });
+        }
+        if !event_reader.poll(poll_interval(timeout, start_time))? {
+            continue;
+        }
+        let event = event_reader.read_event()?;
+        let mut bytes = String::new();
+        if let Event::Key(key_event) = &event {
+            push_key_event(&mut bytes, key_event);
+        }
+        if bytes.is_empty() {
+            event_reader.unconsumed(event);
+            continue;
+        }
+        held.push_back((event, bytes.len()));
+
+        let mut rgb = None;
+        for parsed in parser.feed(bytes.as_bytes()) {
+            match parsed {
+                Parsed::Input(input) => {
+                    let mut len = input.len();
+                    while len > 0 {
+                        let Some((event, event_len)) = held.pop_front() else {
+                            break;
+                        };
+                        len = len.saturating_sub(event_len);
+                        event_reader.unconsumed(event);
+                    }
+                }
+                // The rest of the held events are the reply
+                Parsed::Reply(Reply::Background(x)) => {
+                    held.clear();
+                    rgb = Some(x);
+                }
+                // A reply to another query is left to the caller
+                Parsed::Reply(_) => {
+                    for (event, _) in held.drain(..) {
+                        event_reader.unconsumed(event);
                     }
                 }
             }
         }
+        if let Some(rgb) = rgb {
+            break Ok(rgb);
+        }
+    };
+
+    for (event, _) in held {
+        event_reader.unconsumed(event);
     }
+    ret
 }
 
 fn decode_unterminated(response: &str) -> Result<&str, Error> {
-    let resp_start = response.find("rgb:").ok_or(Error::Parse(
-        "Required string `rgb:` not found in response".to_string(),
-    ))?;
+    let resp_start = response.find("rgb:").ok_or_else(|| Error::MalformedReply {
+        raw: response.to_string(),
+    })?;
     let mid = resp_start + 4;
     // Point after "rgb:"
     let raw_rgb_slice = response.split_at(mid).1;
@@ -387,14 +1880,16 @@ fn decode_unterminated(response: &str) -> Result<&str, Error> {
 
     if fragments.len() < 3 {
         // debug!("Incomplete response `{response}`: does not contain two forward slashes\r");
-        return Err(Error::Parse(format!(
-            "Incomplete response `{response}`: does not contain two forward slashes"
-        )));
+        return Err(Error::MalformedReply {
+            raw: response.to_string(),
+        });
     }
     let frag_len = fragments[0].len();
     if fragments[1].len() != frag_len || fragments[2].len() < frag_len {
         // debug!("Can't safely reconstitute unterminated response `{response}`from fragments of unequal length\r");
-        return Err(Error::Parse(format!("Can't safely reconstitute unterminated response `{response}`from fragments of unequal length")));
+        return Err(Error::MalformedReply {
+            raw: response.to_string(),
+        });
     }
 
     // "Trim" extraneous trailing characters by excluding them from slice
@@ -415,38 +1910,27 @@ fn parse_response(response: &str, start_time: Instant) -> Result<Rgb, Error> {
 fn extract_rgb(response: &str) -> Result<(u16, u16, u16), Error> {
     let rgb_str = response
         .split_at(
-            response.find("rgb:").ok_or(Error::Parse(
-                "Could not find 'rgb:' in terminal response string".to_string(),
-            ))? + 4,
+            response.find("rgb:").ok_or_else(|| Error::MalformedReply {
+                raw: response.to_string(),
+            })? + 4,
         )
         .1;
-    let (r, g, b) = decode_x11_color(rgb_str)?;
+    let (r, g, b) = decode_x11_color(rgb_str).map_err(|_| Error::MalformedReply {
+        raw: response.to_string(),
+    })?;
     // debug!("(r, g, b)=({r}, {g}, {b})\r");
     Ok((r, g, b))
 }
 
-fn restore_raw_status(raw_before: bool) -> Result<(), Error> {
-    let raw_now = is_raw_mode_enabled()?;
-    if raw_now == raw_before {
-        return Ok(());
-    }
-    if raw_before {
-        terminal::enable_raw_mode()?;
-    } else {
-        terminal::disable_raw_mode()?;
-    }
-    Ok(())
-}
-
 /// Discard any unread input returned by the OSC 11 query.
 ///
 /// # Errors
 ///
 /// This function will return an error if Rust has decided that the "terminal" is not a terminal.
 // Helper function to discard extra characters
-fn clear_stdin() -> Result<(), Box<dyn std::error::Error>> {
-    while poll(Duration::from_millis(10))? {
-        if let Event::Key(c) = read()? {
+fn clear_stdin<R: EventReader>(event_reader: &R) -> Result<(), Error> {
+    while event_reader.poll(Duration::from_millis(10))? {
+        if let Event::Key(c) = event_reader.read_event()? {
             // Discard the input by simply reading it
             debug!("discarding char{c:x?}\r");
         }
@@ -459,7 +1943,7 @@ fn from_env_colorfgbg() -> Result<Rgb, Error> {
     let var = env::var("COLORFGBG").map_err(|_| Error::Unsupported)?;
     let fgbg: Vec<_> = var.split(";").collect();
     let bg = fgbg.get(1).ok_or(Error::Unsupported)?;
-    let bg = u8::from_str_radix(bg, 10).map_err(|_| Error::Parse(String::from(var)))?;
+    let bg = bg.parse::<u8>().map_err(|_| Error::Parse(var.clone()))?;
 
     // rxvt default color table
     #[allow(clippy::match_same_arms)]
@@ -508,73 +1992,99 @@ fn from_env_colorfgbg() -> Result<Rgb, Error> {
     })
 }
 
-fn xterm_latency(timeout: Duration) -> Result<Duration, Error> {
-    let query = "\x1b[5n";
-    let mut stderr = io::stderr();
-
-    let raw_before = is_raw_mode_enabled()?;
-
-    defer! {
-        let is_raw = match is_raw_mode_enabled() {
-            Ok(val) => val,
-            Err(e) => {
-                debug!("Failed to check raw mode status: {:?}\r", e);
-                return;
-            }
-        };
-
-        if is_raw == raw_before {
-            debug!("Raw mode status unchanged from raw={raw_before}.\r");
-        } else if let Err(e) = restore_raw_status(raw_before) {
-            debug!("Failed to restore raw mode: {e:?} to raw={raw_before}\r");
-        } else {
-            debug!("Raw mode restored to previous state (raw={raw_before}).\r");
-        }
+/// Measure the latency through `layers` of terminal multiplexers.
+fn xterm_latency<T: TerminalIo + Debug>(
+    io: &T,
+    layers: &[Multiplexer],
+    timeout: Duration,
+) -> Result<Duration, Error> {
+    with_terminal_io(io, |event_reader, writer| {
+        query_latency(layers, timeout, event_reader, writer)
+    })
+}
 
-        if let Err(e) = clear_stdin() {
-            debug!("Failed to clear stdin: {e:?}\r");
-        } else {
-            debug!("Cleared any excess from stdin.\r");
-        }
-    }
+fn xterm_latency_stats<T: TerminalIo + Debug>(
+    io: &T,
+    layers: &[Multiplexer],
+    samples: usize,
+    timeout: Duration,
+) -> Result<LatencyStats, Error> {
+    let samples = with_terminal_io(io, |event_reader, writer| {
+        (0..samples.max(1))
+            .map(|_| query_latency(layers, timeout, event_reader, writer))
+            .collect::<Result<Vec<_>, _>>()
+    })?;
+    debug!("Latency samples: {samples:?}\r");
+
+    Ok(latency_stats_of(samples))
+}
 
-    if !raw_before {
-        terminal::enable_raw_mode()?;
+fn latency_stats_of(mut samples: Vec<Duration>) -> LatencyStats {
+    samples.sort();
+    let percentile = |p: usize| samples[(samples.len() * p).div_ceil(100).max(1) - 1];
+    LatencyStats {
+        min: samples[0],
+        median: percentile(50),
+        p95: percentile(95),
+        max: samples[samples.len() - 1],
     }
+}
 
-    // Send the query
-    stderr.write_all(query.as_bytes())?;
-    stderr.flush()?;
+/// Measure the round trip of DSR (`CSI 5 n`).
+///
+/// The terminal answers `CSI 0 n`, or `CSI 3 n` on malfunction, which is a round trip as well.
+/// A multiplexer answers DSR by itself, so the query is passed through `layers` to measure
+/// the round trip to the terminal emulator.
+fn query_latency<R, W>(
+    layers: &[Multiplexer],
+    timeout: Duration,
+    event_reader: &R,
+    buffer: &mut W,
+) -> Result<Duration, Error>
+where
+    R: EventReader + Debug,
+    W: Write + Debug,
+{
+    // Send query
+    buffer.write_all(&build_query(layers, Query::Status, QueryOptions::default()))?;
+    buffer.flush()?;
 
     let start_time = Instant::now();
+    let response = read_reply(timeout, event_reader, |response| {
+        find_csi_reply(response, "\x1b[", 'n').is_some()
+    })?;
+    let elapsed = start_time.elapsed();
+    debug!("Latency full response: {response:?}\r");
 
-    // Enter raw mode to capture input
-    terminal::enable_raw_mode()?;
-    let mut stdin = io::stdin();
-    let mut response = String::new();
-
-    // Main loop to capture response
-    loop {
-        // Check for timeout
-        if start_time.elapsed() > timeout {
-            terminal::disable_raw_mode()?; // Clean up raw mode
-            return Err(io::Error::new(io::ErrorKind::TimedOut, "timeout 4").into());
-        }
-
-        // Non-blocking read attempt from stdin
-        let mut buffer = [0u8; 1];
-        if stdin.read(&mut buffer).is_ok() {
-            response.push(buffer[0] as char);
-
-            // End the loop once we detect the 'n' character
-            if response.ends_with('n') {
-                let elapsed = start_time.elapsed();
-                debug!("Latency full response: [{response:x?}]\r");
-                // debug!("Elapsed time: {elapsed:?}\r");
+    Ok(elapsed)
+}
 
-                return Ok(elapsed);
-            }
-        }
+/// Parse `#rrggbb` or `#rrrrggggbbbb`.
+///
+/// 8bit colors are expanded to 16bit as `0xff` -> `0xffff`.
+#[cfg(any(not(target_os = "windows"), feature = "serde"))]
+fn parse_hex_color(s: &str) -> Option<Rgb> {
+    let hex = s.strip_prefix('#').filter(|x| x.is_ascii())?;
+    let n = match hex.len() {
+        6 => 2,
+        12 => 4,
+        _ => return None,
+    };
+    let (r, g, b) = decode_x11_color(&format!(
+        "{}/{}/{}",
+        &hex[..n],
+        &hex[n..n * 2],
+        &hex[n * 2..]
+    ))
+    .ok()?;
+    if n == 2 {
+        Some(Rgb {
+            r: r | r >> 8,
+            g: g | g >> 8,
+            b: b | b >> 8,
+        })
+    } else {
+        Some(Rgb { r, g, b })
     }
 }
 
@@ -582,13 +2092,13 @@ fn decode_x11_color(s: &str) -> Result<(u16, u16, u16), Error> {
     fn decode_hex(s: &str) -> Result<u16, Error> {
         let len = s.len() as u32;
         let mut ret = u16::from_str_radix(s, 16).map_err(|_| Error::Parse(String::from(s)))?;
-        ret = ret << ((4 - len) * 4);
+        ret <<= (4 - len) * 4;
         Ok(ret)
     }
 
     let rgb: Vec<_> = s.split('/').collect();
 
-    let r = rgb.get(0).ok_or_else(|| Error::Parse(String::from(s)))?;
+    let r = rgb.first().ok_or_else(|| Error::Parse(String::from(s)))?;
     let g = rgb.get(1).ok_or_else(|| Error::Parse(String::from(s)))?;
     let b = rgb.get(2).ok_or_else(|| Error::Parse(String::from(s)))?;
     let r = decode_hex(r)?;
@@ -695,6 +2205,12 @@ mod tests {
 
     const RGB_RESPONSE_LEN: usize = RGB_RESPONSE.len();
 
+    // Mocked response, optionally followed by a terminator event
+    type ResponseIter<'a> = Either<
+        Cloned<Iter<'a, Event>>,
+        std::iter::Chain<Cloned<Iter<'a, Event>>, iter::Once<Event>>,
+    >;
+
     // Helper method for setting up and invoking call to query_xterm.
     fn run_query_xterm_test(
         emulate_response: bool,
@@ -740,10 +2256,7 @@ mod tests {
         });
 
         let base_iterator = RGB_RESPONSE.iter().cloned();
-        let mut response_iter: Either<
-            Cloned<Iter<'_, Event>>,
-            std::iter::Chain<Cloned<Iter<'_, Event>>, iter::Once<Event>>,
-        > = if let Some(terminator) = maybe_terminator {
+        let mut response_iter: ResponseIter<'_> = if let Some(terminator) = maybe_terminator {
             Either::Right(base_iterator.chain(iter::once(terminator.clone())))
         } else {
             Either::Left(base_iterator)
@@ -771,7 +2284,7 @@ mod tests {
 
         // Run the `query_xterm` function and assert the results
         let result = query_xterm(
-            Terminal::XtermCompatible,
+            &[],
             Duration::from_secs(1),
             &mock_event_reader,
             &mut mock_writer,
@@ -797,7 +2310,7 @@ mod tests {
     // Mock the `Write` trait to use in testing
     mock! {
         #[derive(Debug)]
-        Writer {}
+        pub Writer {}
 
         impl Write for Writer {
             fn write(&mut self, buf: &[u8]) -> io::Result<usize>;
@@ -873,6 +2386,692 @@ mod tests {
         );
     }
 
+    // Convert a reply into the events reported by `crossterm`
+    pub(crate) fn reply_events(reply: &str) -> Vec<Event> {
+        let mut events = Vec::new();
+        let mut chars = reply.chars();
+        while let Some(c) = chars.next() {
+            let key_event = match c {
+                '\x1b' => KeyEvent::new(KeyCode::Char(chars.next().unwrap()), KeyModifiers::ALT),
+                '\x07' => KeyEvent::new(KeyCode::Char('g'), KeyModifiers::CONTROL),
+                c => KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE),
+            };
+            events.push(Event::Key(key_event));
+        }
+        events
+    }
+
+    // Mock an event reader which replies `events` and nothing more
+    pub(crate) fn mock_event_reader(events: Vec<Event>) -> MockEventReader {
+        let events = Arc::new(Mutex::new(events.into_iter()));
+        let remaining = Arc::clone(&events);
+        let mut mock_event_reader = MockEventReader::new();
+        mock_event_reader
+            .expect_poll()
+            .returning(move |_| Ok(remaining.lock().unwrap().len() > 0));
+        mock_event_reader.expect_read_event().returning(move || {
+            events
+                .lock()
+                .unwrap()
+                .next()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "no event").into())
+        });
+        mock_event_reader
+    }
+
+    // Mock a writer which expects `expected` written at once and flushed
+    pub(crate) fn mock_writer(expected: &[u8]) -> MockWriter {
+        let expected = expected.to_vec();
+        let mut mock_writer = MockWriter::new();
+        mock_writer
+            .expect_write()
+            .withf(move |buf| buf == expected.as_slice())
+            .times(1)
+            .returning(|buf| Ok(buf.len()));
+        mock_writer.expect_flush().times(1).returning(|| Ok(()));
+        mock_writer
+    }
+
+    fn run_query_color_scheme_test(
+        layers: &[Multiplexer],
+        query: &'static [u8],
+        reply: &str,
+    ) -> Result<Theme, Error> {
+        let mut mock_writer = mock_writer(query);
+
+        let mock_event_reader = mock_event_reader(reply_events(reply));
+
+        query_color_scheme(
+            layers,
+            Duration::from_millis(300),
+            &mock_event_reader,
+            &mut mock_writer,
+        )
+    }
+
+    #[test]
+    fn test_query_color_scheme() {
+        const QUERY: &[u8] = b"\x1b[?996n\x1b[c";
+        let result = run_query_color_scheme_test(&[], QUERY, "\x1b[?997;1n\x1b[?62;22c");
+        assert_eq!(result.unwrap(), Theme::Dark);
+
+        let result = run_query_color_scheme_test(&[], QUERY, "\x1b[?997;2n\x1b[?62;22c");
+        assert_eq!(result.unwrap(), Theme::Light);
+
+        // DA1 reply without color scheme reply
+        let result = run_query_color_scheme_test(&[], QUERY, "\x1b[?62;22c");
+        assert!(matches!(result, Err(Error::Unsupported)));
+
+        // No reply at all
+        let result = run_query_color_scheme_test(&[], QUERY, "");
+        assert!(matches!(result, Err(Error::Timeout { .. })));
+    }
+
+    #[test]
+    fn test_query_color_scheme_passthrough() {
+        const TMUX_QUERY: &[u8] = b"\x1bPtmux;\x1b\x1b[?996n\x1b\\\x1bPtmux;\x1b\x1b[c\x1b\\";
+        let result =
+            run_query_color_scheme_test(&[Multiplexer::Tmux], TMUX_QUERY, "\x1b[?997;2n\x1b[?1;2c");
+        assert_eq!(result.unwrap(), Theme::Light);
+
+        const SCREEN_QUERY: &[u8] = b"\x1bP\x1b[?996n\x1b\\\x1bP\x1b[c\x1b\\";
+        let result = run_query_color_scheme_test(
+            &[Multiplexer::Screen],
+            SCREEN_QUERY,
+            "\x1b[?997;1n\x1b[?1;2c",
+        );
+        assert_eq!(result.unwrap(), Theme::Dark);
+    }
+
+    #[test]
+    fn test_query_terminal_info() {
+        let mut mock_writer = mock_writer(b"\x1b[>q\x1b[>c\x1b[c");
+
+        let mock_event_reader = mock_event_reader(reply_events(
+            "\x1bP>|XTerm(390)\x1b\\\x1b[>41;390;0c\x1b[?65;1c",
+        ));
+
+        let info = query_terminal_info(
+            &[],
+            Duration::from_millis(300),
+            &mock_event_reader,
+            &mut mock_writer,
+        )
+        .unwrap();
+        assert_eq!(
+            info,
+            TerminalInfo {
+                name: Some("XTerm".to_string()),
+                version: Some("390".to_string()),
+                da2_params: vec![41, 390, 0],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_xtversion() {
+        assert_eq!(
+            parse_xtversion("\x1bP>|kitty(0.36.4)\x1b\\"),
+            Some(("kitty".to_string(), Some("0.36.4".to_string())))
+        );
+        assert_eq!(
+            parse_xtversion("\x1bP>|tmux 3.4\x1b\\"),
+            Some(("tmux".to_string(), Some("3.4".to_string())))
+        );
+        assert_eq!(
+            parse_xtversion("\x1bP>|WezTerm 20240203-110809-5046fc22\x1b\\"),
+            Some((
+                "WezTerm".to_string(),
+                Some("20240203-110809-5046fc22".to_string())
+            ))
+        );
+        assert_eq!(
+            parse_xtversion("\x1bP>|mlterm\x1b\\"),
+            Some(("mlterm".to_string(), None))
+        );
+        assert_eq!(parse_xtversion("\x1b[>1;10;0c"), None);
+    }
+
+    fn run_query_osc_color_test(
+        slot: ColorSlot,
+        query: &'static [u8],
+        reply: &str,
+    ) -> Result<Rgb, Error> {
+        let mut mock_writer = mock_writer(query);
+
+        let mock_event_reader = mock_event_reader(reply_events(reply));
+
+        query_osc_color(
+            &[],
+            slot,
+            Duration::from_millis(300),
+            &mock_event_reader,
+            &mut mock_writer,
+        )
+    }
+
+    #[test]
+    fn test_query_osc_color() {
+        const QUERY: &[u8] = b"\x1b]11;?\x07\x1b[c";
+        let result = run_query_osc_color_test(
+            ColorSlot::Background,
+            QUERY,
+            "\x1b]11;rgb:1e1e/1e1e/2e2e\x07\x1b[?62;22c",
+        );
+        assert_eq!(
+            result.unwrap(),
+            Rgb {
+                r: 0x1e1e,
+                g: 0x1e1e,
+                b: 0x2e2e
+            }
+        );
+
+        // DA1 reply without OSC 11 reply
+        let result = run_query_osc_color_test(ColorSlot::Background, QUERY, "\x1b[?62;22c");
+        assert!(matches!(result, Err(Error::Unsupported)));
+
+        const PALETTE_QUERY: &[u8] = b"\x1b]4;1;?\x07\x1b[c";
+        let result = run_query_osc_color_test(
+            ColorSlot::Palette(1),
+            PALETTE_QUERY,
+            "\x1b]4;1;rgb:cdcd/0000/0000\x1b\\\x1b[?62;22c",
+        );
+        assert_eq!(
+            result.unwrap(),
+            Rgb {
+                r: 0xcdcd,
+                g: 0,
+                b: 0
+            }
+        );
+    }
+
+    fn run_query_latency_test(reply: &str) -> Result<Duration, Error> {
+        let mut mock_writer = mock_writer(b"\x1b[5n");
+
+        let mock_event_reader = mock_event_reader(reply_events(reply));
+
+        query_latency(
+            &[],
+            Duration::from_millis(100),
+            &mock_event_reader,
+            &mut mock_writer,
+        )
+    }
+
+    #[test]
+    fn test_query_latency() {
+        assert!(run_query_latency_test("\x1b[0n").is_ok());
+        assert!(run_query_latency_test("\x1b[3n").is_ok());
+
+        // Other replies are skipped
+        assert!(run_query_latency_test("\x1b[?997;1n\x1b[0n").is_ok());
+
+        let result = run_query_latency_test("\x1b[?62;22c");
+        assert!(matches!(
+            result,
+            Err(Error::Timeout { partial_response, .. }) if partial_response == "\x1b[?62;22c"
+        ));
+    }
+
+    #[test]
+    fn test_xterm_latency_through_multiplexers() {
+        let io = MemoryIo::new(b"\x1b[0n");
+        assert!(xterm_latency(&io, &[Multiplexer::Tmux], Duration::from_millis(100)).is_ok());
+        assert_eq!(io.output(), b"\x1bPtmux;\x1b\x1b[5n\x1b\\");
+    }
+
+    #[test]
+    fn test_latency_stats_of() {
+        let ms = Duration::from_millis;
+        let stats = latency_stats_of((1..=20).rev().map(ms).collect());
+        assert_eq!(
+            stats,
+            LatencyStats {
+                min: ms(1),
+                median: ms(10),
+                p95: ms(19),
+                max: ms(20),
+            }
+        );
+
+        let stats = latency_stats_of(vec![ms(5)]);
+        assert_eq!(stats.min, ms(5));
+        assert_eq!(stats.median, ms(5));
+        assert_eq!(stats.p95, ms(5));
+        assert_eq!(stats.max, ms(5));
+    }
+
+    #[test]
+    fn test_find_da1_reply() {
+        assert_eq!(find_da1_reply("\x1b[?62;22c"), Some("62;22"));
+        assert_eq!(find_da1_reply("\x1b[?997;1n\x1b[?1;2c"), Some("1;2"));
+        assert_eq!(find_da1_reply("\x1b[?997;1n"), None);
+        assert_eq!(find_da1_reply("\x1b[?62;2"), None);
+    }
+
+    // Look up environment variables in `vars` instead of the process environment
+    fn env_from<'a>(vars: &'a [(&str, &str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        move |key| {
+            vars.iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, v)| v.to_string())
+        }
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    fn run_terminal_from_env_test(vars: &[(&str, &str)]) -> Terminal {
+        terminal_from_env(env_from(vars), Vec::new)
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn test_terminal_from_env() {
+        let cases: &[(&[(&str, &str)], Terminal)] = &[
+            (&[("TERM", "xterm-256color")], Terminal::XtermCompatible),
+            (&[], Terminal::XtermCompatible),
+            (&[("TERM", "dumb")], Terminal::Dumb),
+            (&[("TERM", "linux")], Terminal::LinuxConsole),
+            (&[("TERM", "screen.xterm-256color")], Terminal::Screen),
+            (&[("TERM", "tmux-256color")], Terminal::Tmux),
+            (
+                &[("TERM", "screen"), ("TMUX", "/tmp/tmux-1000/default,1,0")],
+                Terminal::Tmux,
+            ),
+            (
+                &[
+                    ("TERM", "screen"),
+                    ("TMUX", "/tmp/tmux"),
+                    ("STY", "1.pts-0"),
+                ],
+                Terminal::TmuxInScreen,
+            ),
+            (
+                &[
+                    ("TERM", "tmux-256color"),
+                    ("TMUX", "/tmp/tmux-1000/default,200,0"),
+                    ("STY", "100.pts-0.host"),
+                ],
+                Terminal::TmuxInScreen,
+            ),
+            // GNU Screen inside tmux
+            (
+                &[
+                    ("TERM", "screen.tmux-256color"),
+                    ("TMUX", "/tmp/tmux-1000/default,100,0"),
+                    ("STY", "200.pts-1.host"),
+                ],
+                Terminal::Screen,
+            ),
+            (
+                &[("TERM", "xterm-256color"), ("ZELLIJ", "0")],
+                Terminal::Zellij,
+            ),
+            (
+                &[("TERM", "xterm-256color"), ("ZELLIJ_SESSION_NAME", "main")],
+                Terminal::Zellij,
+            ),
+            // tmux inside Zellij
+            (
+                &[
+                    ("TERM", "tmux-256color"),
+                    ("TMUX", "/tmp/tmux-1000/default,200,0"),
+                    ("ZELLIJ", "0"),
+                ],
+                Terminal::Tmux,
+            ),
+            (
+                &[("TERM", "xterm-256color"), ("TERM_PROGRAM", "vscode")],
+                Terminal::VsCode,
+            ),
+            (
+                &[("TERMINAL_EMULATOR", "JetBrains-JediTerm")],
+                Terminal::JetBrains,
+            ),
+            (&[("TERM", "xterm-kitty")], Terminal::Kitty),
+            (&[("KITTY_WINDOW_ID", "1")], Terminal::Kitty),
+            (&[("TERM_PROGRAM", "WezTerm")], Terminal::WezTerm),
+            (&[("WEZTERM_PANE", "0")], Terminal::WezTerm),
+            (&[("TERM", "alacritty")], Terminal::Alacritty),
+            (&[("TERM", "foot-extra")], Terminal::Foot),
+            (&[("VTE_VERSION", "7600")], Terminal::Vte),
+            (
+                &[("INSIDE_EMACS", "29.1,comint"), ("TERM", "dumb")],
+                Terminal::Emacs,
+            ),
+        ];
+
+        for (vars, expected) in cases {
+            assert_eq!(run_terminal_from_env_test(vars), *expected, "{vars:?}");
+        }
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn test_is_tmux_nearer() {
+        let run = |vars: &[(&str, &str)], ancestors: &[u32]| {
+            is_tmux_nearer(env_from(vars), || {
+                ancestors.iter().map(|pid| (*pid, String::new())).collect()
+            })
+        };
+
+        // tmux (pid 200) inside GNU Screen (pid 100)
+        let vars = [
+            ("TERM", "screen"),
+            ("TMUX", "/tmp/tmux-1000/default,200,0"),
+            ("STY", "100.pts-0.host"),
+        ];
+        assert!(run(&vars, &[200, 50]));
+
+        // GNU Screen (pid 200) inside tmux (pid 100)
+        let vars = [
+            ("TERM", "screen"),
+            ("TMUX", "/tmp/tmux-1000/default,100,0"),
+            ("STY", "200.pts-1.host"),
+        ];
+        assert!(!run(&vars, &[300, 200, 100]));
+
+        // Unknown
+        assert!(run(&vars, &[]));
+        assert!(!run(&[("TERM", "screen.xterm-256color")], &[]));
+        assert!(run(&[("TERM", "tmux-256color")], &[]));
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn test_is_zellij_nearer() {
+        let run = |ancestors: &[(u32, &str)]| {
+            let vars = [
+                ("TERM", "tmux-256color"),
+                ("TMUX", "/tmp/tmux-1000/default,200,0"),
+                ("ZELLIJ", "0"),
+            ];
+            is_zellij_nearer(env_from(&vars), || {
+                ancestors
+                    .iter()
+                    .map(|(pid, comm)| (*pid, comm.to_string()))
+                    .collect()
+            })
+        };
+
+        // tmux (pid 200) inside Zellij
+        assert!(!run(&[(300, "zsh"), (200, "tmux: server")]));
+        // Zellij inside tmux (pid 200)
+        assert!(run(&[
+            (300, "zsh"),
+            (250, "/usr/bin/zellij"),
+            (200, "tmux")
+        ]));
+        // Unknown
+        assert!(!run(&[]));
+    }
+
+    #[test]
+    fn test_preflight_from_env() {
+        let run = |vars: &[(&str, &str)], non_terminal_stream: Option<Stream>| {
+            preflight_from_env(env_from(vars), non_terminal_stream)
+        };
+
+        assert!(run(&[("TERM", "xterm-256color")], None).is_ok());
+        assert!(run(&[("TERM", "xterm-256color"), ("NO_COLOR", "")], None).is_ok());
+        assert!(run(&[("TERM", "xterm-256color"), ("CI", "true")], None).is_ok());
+        assert!(matches!(
+            run(&[("TERM", "dumb")], None),
+            Err(Error::DumbTerminal)
+        ));
+        assert!(matches!(
+            run(&[("TERM", "xterm-256color"), ("NO_COLOR", "1")], None),
+            Err(Error::DumbTerminal)
+        ));
+        assert!(matches!(
+            run(&[("CI", "true")], None),
+            Err(Error::DumbTerminal)
+        ));
+        assert!(run(&[("TERM", "dumb"), ("INSIDE_EMACS", "29.1,comint")], None).is_ok());
+        assert!(matches!(
+            run(&[("TERM", "xterm-256color")], Some(Stream::Stdout)),
+            Err(Error::NotATerminal {
+                stream: Stream::Stdout
+            })
+        ));
+    }
+
+    #[test]
+    fn test_error_display() {
+        let err = Error::Timeout {
+            elapsed: Duration::from_millis(100),
+            partial_response: "\x1b]11;rgb:ffff".to_string(),
+        };
+        assert_eq!(
+            err.to_string(),
+            "no complete reply within 100.00ms (partial response: \"\\u{1b}]11;rgb:ffff\")"
+        );
+
+        let err = Error::NotATerminal {
+            stream: Stream::Stdin,
+        };
+        assert_eq!(err.to_string(), "stdin is not a terminal");
+
+        let err = Error::UnsupportedTerminal(Terminal::TmuxInScreen);
+        assert_eq!(
+            err.to_string(),
+            "terminal tmux_in_screen doesn't support the query"
+        );
+    }
+
+    #[test]
+    fn test_wrap_passthrough() {
+        const QUERY: &[u8] = b"\x1b]11;?\x07";
+        assert_eq!(wrap_passthrough(&[], QUERY), b"\x1b]11;?\x07");
+        assert_eq!(
+            wrap_passthrough(&[Multiplexer::Tmux], QUERY),
+            b"\x1bPtmux;\x1b\x1b]11;?\x07\x1b\\"
+        );
+        assert_eq!(
+            wrap_passthrough(&[Multiplexer::Screen], QUERY),
+            b"\x1bP\x1b]11;?\x07\x1b\\"
+        );
+        assert_eq!(
+            wrap_passthrough(&[Multiplexer::Tmux, Multiplexer::Tmux], QUERY),
+            b"\x1bPtmux;\x1b\x1bPtmux;\x1b\x1b\x1b\x1b]11;?\x07\x1b\x1b\\\x1b\\"
+        );
+        assert_eq!(
+            wrap_passthrough(&[Multiplexer::Tmux, Multiplexer::Screen], QUERY),
+            b"\x1bPtmux;\x1b\x1bP\x1b\x1b]11;?\x07\x1b\x1b\\\x1b\\"
+        );
+    }
+
+    #[test]
+    fn test_remote_from_env() {
+        let run =
+            |vars: &[(&str, &str)], is_mosh: bool| remote_from_env(env_from(vars), || is_mosh);
+
+        assert_eq!(run(&[], false), None);
+        // mosh is not looked for without SSH
+        assert_eq!(run(&[], true), None);
+        assert_eq!(run(&[("SSH_TTY", "/dev/pts/0")], false), Some(Remote::Ssh));
+        assert_eq!(
+            run(&[("SSH_CONNECTION", "10.0.0.1 22 10.0.0.2 22")], true),
+            Some(Remote::Mosh)
+        );
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn test_walk_ancestors() {
+        let processes = parse_ps_processes(
+            "    1     0 init\n  100     1 mosh-server\n  200   100 /bin/zsh\n  300   200 my app\n",
+        );
+        assert_eq!(processes[&300], (200, "my app".to_string()));
+
+        let ancestors = walk_ancestors(300, |pid| processes.get(&pid).cloned());
+        assert_eq!(
+            ancestors,
+            vec![
+                (200, "/bin/zsh".to_string()),
+                (100, "mosh-server".to_string())
+            ]
+        );
+
+        // A cycle by pid reuse
+        let ancestors = walk_ancestors(2, |pid| Some((pid ^ 1, String::new())));
+        assert_eq!(ancestors.len(), 64);
+
+        #[cfg(target_os = "linux")]
+        assert_eq!(
+            proc_process(std::process::id()).map(|(ppid, _)| ppid),
+            Some(std::os::unix::process::parent_id())
+        );
+    }
+
+    #[test]
+    fn test_adapt_timeout() {
+        let ms = Duration::from_millis;
+        assert_eq!(adapt_timeout(ms(100), ms(2)), ms(100));
+        assert_eq!(adapt_timeout(ms(100), ms(80)), ms(320));
+    }
+
+    #[test]
+    fn test_timeout_from_latency() {
+        let ms = Duration::from_millis;
+        assert_eq!(timeout_from_latency(ms(1)), AUTO_TIMEOUT_MIN);
+        assert_eq!(timeout_from_latency(ms(30)), ms(120));
+        assert_eq!(timeout_from_latency(ms(500)), AUTO_TIMEOUT_MAX);
+        assert_eq!(timeout_from_latency(AUTO_PROBE_TIMEOUT), AUTO_TIMEOUT_MAX);
+    }
+
+    #[test]
+    fn test_multiplexers_from_env() {
+        let run = |term: Terminal, vars: &[(&str, &str)], client_term: Option<(&str, &str)>| {
+            multiplexers_from_env(term, env_from(vars), || {
+                client_term.map(|(name, termtype)| (name.to_string(), termtype.to_string()))
+            })
+        };
+
+        assert_eq!(run(Terminal::XtermCompatible, &[], None), vec![]);
+        assert_eq!(
+            run(Terminal::Tmux, &[], Some(("tmux-256color", ""))),
+            vec![Multiplexer::Tmux]
+        );
+        assert_eq!(
+            run(Terminal::TmuxInScreen, &[], None),
+            vec![Multiplexer::Tmux, Multiplexer::Screen]
+        );
+        assert_eq!(
+            run(
+                Terminal::Tmux,
+                &[("SSH_CONNECTION", "10.0.0.1 22 10.0.0.2 22")],
+                Some(("tmux-256color", ""))
+            ),
+            vec![Multiplexer::Tmux, Multiplexer::Tmux]
+        );
+        assert_eq!(
+            run(
+                Terminal::Tmux,
+                &[("SSH_TTY", "/dev/pts/1")],
+                Some(("xterm-256color", "XTerm(390)"))
+            ),
+            vec![Multiplexer::Tmux]
+        );
+
+        // `TERM=screen` set by tmux
+        let ssh = [("SSH_TTY", "/dev/pts/1")];
+        assert_eq!(
+            run(Terminal::Tmux, &ssh, Some(("screen", "tmux 3.4"))),
+            vec![Multiplexer::Tmux, Multiplexer::Tmux]
+        );
+        assert_eq!(
+            run(Terminal::Tmux, &ssh, Some(("screen", ""))),
+            vec![Multiplexer::Tmux]
+        );
+        assert_eq!(
+            run(Terminal::Tmux, &ssh, Some(("screen.xterm-256color", ""))),
+            vec![Multiplexer::Tmux, Multiplexer::Screen]
+        );
+    }
+
+    #[cfg(any(not(target_os = "windows"), feature = "serde"))]
+    #[test]
+    fn test_parse_hex_color() {
+        assert_eq!(
+            parse_hex_color("#1e1e2e"),
+            Some(Rgb {
+                r: 0x1e1e,
+                g: 0x1e1e,
+                b: 0x2e2e
+            })
+        );
+        assert_eq!(
+            parse_hex_color("#12345678abcd"),
+            Some(Rgb {
+                r: 0x1234,
+                g: 0x5678,
+                b: 0xabcd
+            })
+        );
+        assert_eq!(parse_hex_color("1e1e2e"), None);
+        assert_eq!(parse_hex_color("#1e1e2"), None);
+        assert_eq!(parse_hex_color("#1e1e2g"), None);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let rgb = Rgb {
+            r: 0x1e1e,
+            g: 0x1e1e,
+            b: 0x2e2e,
+        };
+        assert_eq!(serde_json::to_string(&rgb).unwrap(), "\"#1e1e2e\"");
+        let rgb = Rgb {
+            r: 0x1234,
+            g: 0x5678,
+            b: 0xabcd,
+        };
+        assert_eq!(serde_json::to_string(&rgb).unwrap(), "\"#12345678abcd\"");
+        assert_eq!(
+            serde_json::from_str::<Rgb>("\"#12345678abcd\"").unwrap(),
+            rgb
+        );
+        assert!(serde_json::from_str::<Rgb>("\"black\"").is_err());
+
+        assert_eq!(serde_json::to_string(&Theme::Dark).unwrap(), "\"dark\"");
+        assert_eq!(
+            serde_json::from_str::<Theme>("\"light\"").unwrap(),
+            Theme::Light
+        );
+        assert_eq!(
+            serde_json::to_string(&Terminal::TmuxInScreen).unwrap(),
+            "\"tmux_in_screen\""
+        );
+        assert_eq!(
+            serde_json::from_str::<Terminal>("\"xterm_compatible\"").unwrap(),
+            Terminal::XtermCompatible
+        );
+
+        // `as_str()` is the serialized name
+        for terminal in [
+            Terminal::TmuxInScreen,
+            Terminal::XtermCompatible,
+            Terminal::VsCode,
+            Terminal::JetBrains,
+            Terminal::WezTerm,
+            Terminal::LinuxConsole,
+        ] {
+            assert_eq!(
+                serde_json::to_string(&terminal).unwrap(),
+                format!("\"{}\"", terminal.as_str())
+            );
+        }
+        for source in [Source::ColorScheme, Source::Osc11, Source::WinApi] {
+            assert_eq!(
+                serde_json::to_string(&source).unwrap(),
+                format!("\"{}\"", source.as_str())
+            );
+        }
+    }
+
     #[test]
     fn test_decode_x11_color() {
         let s = "0000/0000/0000";
@@ -0,0 +1,260 @@
+use crate::{
+    query_layers, terminal, wrap_passthrough, Capabilities, Multiplexer, ReplyTerminator, Terminal,
+};
+
+/// Query sent to the terminal
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Query {
+    /// Foreground color by `OSC 10 ; ?`
+    Foreground,
+    /// Background color by `OSC 11 ; ?`
+    Background,
+    /// Palette color by `OSC 4 ; index ; ?`
+    Palette(u8),
+    /// Primary device attributes by `CSI c`
+    DeviceAttributes,
+    /// Secondary device attributes by `CSI > c`
+    SecondaryDeviceAttributes,
+    /// Terminal name and version by XTVERSION (`CSI > q`)
+    Version,
+    /// Device status by `CSI 5 n`
+    Status,
+    /// Preferred color scheme by `CSI ? 996 n`
+    ColorScheme,
+    /// Whether color scheme updates (mode 2031) are enabled, by `CSI ? 2031 $ p`
+    ColorSchemeUpdates,
+}
+
+/// Color slot queried by OSC
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ColorSlot {
+    /// `OSC 10`
+    Foreground,
+    /// `OSC 11`
+    Background,
+    /// `OSC 4 ; index`
+    Palette(u8),
+}
+
+impl ColorSlot {
+    /// `Ps` of `OSC Ps ; ?`, like `11` or `4;1`
+    pub(crate) fn osc(self) -> String {
+        match self {
+            ColorSlot::Foreground => "10".to_string(),
+            ColorSlot::Background => "11".to_string(),
+            ColorSlot::Palette(index) => format!("4;{index}"),
+        }
+    }
+}
+
+impl From<ColorSlot> for Query {
+    fn from(slot: ColorSlot) -> Self {
+        match slot {
+            ColorSlot::Foreground => Query::Foreground,
+            ColorSlot::Background => Query::Background,
+            ColorSlot::Palette(index) => Query::Palette(index),
+        }
+    }
+}
+
+/// Terminator of OSC queries
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum QueryTerminator {
+    /// `BEL` (`0x07`), accepted by all terminals
+    Bel,
+    /// `ST` (`ESC \` or `0x9c`)
+    St,
+}
+
+/// Encoding of control sequence introducers and `ST`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Controls {
+    /// `ESC [`, `ESC ]` and `ESC \`
+    SevenBit,
+    /// `0x9b`, `0x9d` and `0x9c`, not accepted by terminals expecting UTF-8
+    EightBit,
+}
+
+/// Options of query sequences
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct QueryOptions {
+    pub terminator: QueryTerminator,
+    pub controls: Controls,
+}
+
+impl Default for QueryOptions {
+    fn default() -> Self {
+        QueryOptions {
+            terminator: QueryTerminator::Bel,
+            controls: Controls::SevenBit,
+        }
+    }
+}
+
+impl QueryOptions {
+    /// Options suited to `terminal` behind `layers` of multiplexers
+    ///
+    /// OSC queries are terminated by ST, except through multiplexers and on terminals
+    /// replying by BEL.
+    pub fn for_terminal(terminal: Terminal, layers: &[Multiplexer]) -> Self {
+        let options = QueryOptions::for_layers(layers);
+        if Capabilities::from(terminal).terminator == ReplyTerminator::Bel {
+            QueryOptions {
+                terminator: QueryTerminator::Bel,
+                ..options
+            }
+        } else {
+            options
+        }
+    }
+
+    /// GNU Screen ends passthrough at the first ST, so BEL is used through multiplexers.
+    pub(crate) fn for_layers(layers: &[Multiplexer]) -> Self {
+        let terminator = if layers.is_empty() {
+            QueryTerminator::St
+        } else {
+            QueryTerminator::Bel
+        };
+        QueryOptions {
+            terminator,
+            controls: Controls::SevenBit,
+        }
+    }
+}
+
+/// get the OSC query sequence of `slot` for `terminal` behind `layers` of multiplexers
+///
+/// `layers` are typically `query_multiplexers()`. The query is wrapped for passthrough of `layers`,
+/// and terminated by `QueryOptions::for_terminal()`.
+pub fn osc_query(slot: ColorSlot, terminal: Terminal, layers: &[Multiplexer]) -> Vec<u8> {
+    query_bytes(
+        slot.into(),
+        layers,
+        QueryOptions::for_terminal(terminal, layers),
+    )
+}
+
+/// get the query sequence of `query` wrapped for `layers` of multiplexers with `options`
+///
+/// The wrapping for multiplexers always uses 7-bit controls.
+pub fn query_bytes(query: Query, layers: &[Multiplexer], options: QueryOptions) -> Vec<u8> {
+    build_query(layers, query, options)
+}
+
+/// get the query sequence for `query`, wrapped for the multiplexers
+///
+/// OSC queries are terminated by BEL, and every query is wrapped for passthrough of
+/// the multiplexers which the library queries through, as `query_multiplexers()`.
+pub fn query_sequence(query: Query) -> Vec<u8> {
+    build_query(&query_layers(terminal()), query, QueryOptions::default())
+}
+
+pub(crate) fn build_query(layers: &[Multiplexer], query: Query, options: QueryOptions) -> Vec<u8> {
+    let (csi, osc, st): (&[u8], &[u8], &[u8]) = match options.controls {
+        Controls::SevenBit => (b"\x1b[", b"\x1b]", b"\x1b\\"),
+        Controls::EightBit => (b"\x9b", b"\x9d", b"\x9c"),
+    };
+    let terminator = match options.terminator {
+        QueryTerminator::Bel => b"\x07",
+        QueryTerminator::St => st,
+    };
+    let query = match query {
+        Query::Foreground => [osc, b"10;?", terminator].concat(),
+        Query::Background => [osc, b"11;?", terminator].concat(),
+        Query::Palette(index) => [osc, format!("4;{index};?").as_bytes(), terminator].concat(),
+        Query::DeviceAttributes => [csi, b"c"].concat(),
+        Query::SecondaryDeviceAttributes => [csi, b">c"].concat(),
+        Query::Version => [csi, b">q"].concat(),
+        Query::Status => [csi, b"5n"].concat(),
+        Query::ColorScheme => [csi, b"?996n"].concat(),
+        Query::ColorSchemeUpdates => [csi, b"?2031$p"].concat(),
+    };
+    wrap_passthrough(layers, &query)
+}
+
+/// Build `queries` sent at once, each wrapped for `layers` separately.
+pub(crate) fn build_queries(
+    layers: &[Multiplexer],
+    queries: &[Query],
+    options: QueryOptions,
+) -> Vec<u8> {
+    queries
+        .iter()
+        .flat_map(|&query| build_query(layers, query, options))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_query() {
+        let st = QueryOptions {
+            terminator: QueryTerminator::St,
+            controls: Controls::SevenBit,
+        };
+        let eight_bit = QueryOptions {
+            terminator: QueryTerminator::St,
+            controls: Controls::EightBit,
+        };
+        let bel = QueryOptions::default();
+
+        assert_eq!(build_query(&[], Query::Background, st), b"\x1b]11;?\x1b\\");
+        assert_eq!(build_query(&[], Query::Foreground, bel), b"\x1b]10;?\x07");
+        assert_eq!(
+            build_query(&[], Query::Palette(12), eight_bit),
+            b"\x9d4;12;?\x9c"
+        );
+        assert_eq!(build_query(&[], Query::Status, eight_bit), b"\x9b5n");
+        assert_eq!(
+            build_query(&[], Query::ColorSchemeUpdates, bel),
+            b"\x1b[?2031$p"
+        );
+        assert_eq!(
+            build_query(&[Multiplexer::Tmux], Query::Background, bel),
+            b"\x1bPtmux;\x1b\x1b]11;?\x07\x1b\\"
+        );
+        assert_eq!(
+            build_query(&[Multiplexer::Screen], Query::DeviceAttributes, eight_bit),
+            b"\x1bP\x9bc\x1b\\"
+        );
+        assert_eq!(
+            build_queries(
+                &[],
+                &[Query::Version, Query::SecondaryDeviceAttributes],
+                bel
+            ),
+            b"\x1b[>q\x1b[>c"
+        );
+    }
+
+    #[test]
+    fn test_osc_query() {
+        assert_eq!(
+            osc_query(ColorSlot::Background, Terminal::Kitty, &[]),
+            b"\x1b]11;?\x1b\\"
+        );
+        assert_eq!(
+            osc_query(ColorSlot::Palette(1), Terminal::Zellij, &[]),
+            b"\x1b]4;1;?\x07"
+        );
+        assert_eq!(
+            osc_query(ColorSlot::Foreground, Terminal::Tmux, &[Multiplexer::Tmux]),
+            b"\x1bPtmux;\x1b\x1b]10;?\x07\x1b\\"
+        );
+    }
+
+    #[test]
+    fn test_query_options_for_layers() {
+        assert_eq!(
+            QueryOptions::for_layers(&[]).terminator,
+            QueryTerminator::St
+        );
+        assert_eq!(
+            QueryOptions::for_layers(&[Multiplexer::Screen]).terminator,
+            QueryTerminator::Bel
+        );
+    }
+}
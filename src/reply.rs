@@ -0,0 +1,273 @@
+use crate::{decode_x11_color, Rgb, Theme};
+use std::mem;
+
+/// Longest sequence held as a possible reply. Longer ones are passed through.
+const MAX_REPLY_LEN: usize = 256;
+
+/// Reply extracted by `ReplyParser`
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Reply {
+    /// `OSC 10 ; rgb:r/g/b`
+    Foreground(Rgb),
+    /// `OSC 11 ; rgb:r/g/b`
+    Background(Rgb),
+    /// `OSC 4 ; index ; rgb:r/g/b`
+    Palette { index: u8, rgb: Rgb },
+    /// `CSI ? Ps ; ... c` with the parameters
+    DeviceAttributes(Vec<u16>),
+    /// `CSI Ps n`, where `0` is OK
+    Status(u16),
+    /// `CSI ? 997 ; Ps n`, answered to `CSI ? 996 n` or notified in mode 2031
+    ColorScheme(Theme),
+    /// `CSI ? 2031 ; Ps $ y`, where `1` is set, `2` is reset and `0` is not recognized
+    ColorSchemeUpdates(u16),
+}
+
+/// Output of `ReplyParser`, in the order of input
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Parsed {
+    /// Bytes which are not a reply, passed through untouched
+    Input(Vec<u8>),
+    Reply(Reply),
+}
+
+/// A parser extracting replies from the input bytes read by the application.
+///
+/// This is for applications which own the input loop. The application writes the queries
+/// produced by `query_sequence()` or `query_bytes()`, feeds every input byte to `feed()`, and handles the
+/// returned `Parsed::Input` as usual.
+///
+/// An escape sequence split across chunks is held until the next `feed()`.
+/// A lone ESC is held as well, so call `flush()` when no more input arrives in a while.
+#[derive(Debug, Default)]
+pub struct ReplyParser {
+    pending: Vec<u8>,
+}
+
+impl ReplyParser {
+    pub fn new() -> Self {
+        ReplyParser::default()
+    }
+
+    /// Feed input bytes, and get the replies and the other bytes in the order of input.
+    pub fn feed(&mut self, input: &[u8]) -> Vec<Parsed> {
+        let mut ret = Vec::new();
+        let mut other = Vec::new();
+        for &byte in input {
+            self.push(byte, &mut ret, &mut other);
+        }
+        if !other.is_empty() {
+            ret.push(Parsed::Input(other));
+        }
+        ret
+    }
+
+    fn push(&mut self, byte: u8, ret: &mut Vec<Parsed>, other: &mut Vec<u8>) {
+        if self.pending.is_empty() && byte != 0x1b {
+            other.push(byte);
+            return;
+        }
+        self.pending.push(byte);
+        let reply = match scan(&self.pending) {
+            Scan::Incomplete if self.pending.len() < MAX_REPLY_LEN => return,
+            Scan::Complete => parse_reply(&self.pending),
+            Scan::Incomplete | Scan::NotReply => None,
+        };
+        match reply {
+            Some(reply) => {
+                if !other.is_empty() {
+                    ret.push(Parsed::Input(mem::take(other)));
+                }
+                ret.push(Parsed::Reply(reply));
+                self.pending.clear();
+            }
+            None => {
+                // Start over from the next ESC, which may introduce a reply
+                let next_esc = self.pending[1..].iter().position(|&x| x == 0x1b);
+                let rest = match next_esc {
+                    Some(pos) => self.pending.split_off(pos + 1),
+                    None => Vec::new(),
+                };
+                other.append(&mut self.pending);
+                for byte in rest {
+                    self.push(byte, ret, other);
+                }
+            }
+        }
+    }
+
+    /// Take the bytes held as a possible reply.
+    pub fn flush(&mut self) -> Vec<u8> {
+        mem::take(&mut self.pending)
+    }
+}
+
+enum Scan {
+    Incomplete,
+    Complete,
+    NotReply,
+}
+
+/// Check whether `seq` starting with ESC is a complete OSC or CSI sequence.
+fn scan(seq: &[u8]) -> Scan {
+    let last = seq[seq.len() - 1];
+    match seq.get(1) {
+        None => Scan::Incomplete,
+        Some(b']') => match last {
+            0x07 => Scan::Complete,
+            // ESC in the middle is the start of ST
+            _ if seq.len() > 3 && seq[seq.len() - 2] == 0x1b => {
+                if last == b'\\' {
+                    Scan::Complete
+                } else {
+                    Scan::NotReply
+                }
+            }
+            0x1b => Scan::Incomplete,
+            _ if last < 0x20 => Scan::NotReply,
+            _ => Scan::Incomplete,
+        },
+        Some(b'[') => match last {
+            _ if seq.len() == 2 => Scan::Incomplete,
+            0x40..=0x7e => Scan::Complete,
+            0x20..=0x3f => Scan::Incomplete,
+            _ => Scan::NotReply,
+        },
+        Some(_) => Scan::NotReply,
+    }
+}
+
+fn parse_reply(seq: &[u8]) -> Option<Reply> {
+    let seq = std::str::from_utf8(seq).ok()?;
+    if let Some(body) = seq.strip_prefix("\x1b]") {
+        let body = body
+            .strip_suffix('\x07')
+            .or_else(|| body.strip_suffix("\x1b\\"))?;
+        parse_osc(body)
+    } else {
+        parse_csi(seq.strip_prefix("\x1b[")?)
+    }
+}
+
+fn parse_osc(body: &str) -> Option<Reply> {
+    let (slot, color) = body.split_once(';')?;
+    match slot {
+        "10" => Some(Reply::Foreground(parse_rgb(color)?)),
+        "11" => Some(Reply::Background(parse_rgb(color)?)),
+        "4" => {
+            let (index, color) = color.split_once(';')?;
+            Some(Reply::Palette {
+                index: index.parse().ok()?,
+                rgb: parse_rgb(color)?,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Parse `rgb:r/g/b` with 1 to 4 hex digits for each component.
+fn parse_rgb(color: &str) -> Option<Rgb> {
+    let rgb = color.strip_prefix("rgb:")?;
+    let is_valid = rgb
+        .split('/')
+        .all(|x| (1..=4).contains(&x.len()) && x.chars().all(|c| c.is_ascii_hexdigit()));
+    if !is_valid {
+        return None;
+    }
+    let (r, g, b) = decode_x11_color(rgb).ok()?;
+    Some(Rgb { r, g, b })
+}
+
+fn parse_csi(body: &str) -> Option<Reply> {
+    let params = |s: &str| -> Option<Vec<u16>> { s.split(';').map(|x| x.parse().ok()).collect() };
+    if let Some(attrs) = body.strip_prefix('?').and_then(|x| x.strip_suffix('c')) {
+        return Some(Reply::DeviceAttributes(params(attrs)?));
+    }
+    if let Some(mode) = body
+        .strip_prefix("?2031;")
+        .and_then(|x| x.strip_suffix("$y"))
+    {
+        return Some(Reply::ColorSchemeUpdates(mode.parse().ok()?));
+    }
+    match body {
+        "?997;1n" => Some(Reply::ColorScheme(Theme::Dark)),
+        "?997;2n" => Some(Reply::ColorScheme(Theme::Light)),
+        _ => {
+            let status = body.strip_suffix('n')?;
+            Some(Reply::Status(status.parse().ok()?))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed_all(parser: &mut ReplyParser, chunks: &[&[u8]]) -> Vec<Parsed> {
+        chunks.iter().flat_map(|x| parser.feed(x)).collect()
+    }
+
+    #[test]
+    fn test_reply_parser() {
+        let mut parser = ReplyParser::new();
+        let parsed = feed_all(
+            &mut parser,
+            &[
+                b"ab\x1b]11;rgb:1e1e/1e1e/2e",
+                b"2e\x1b\\c\x1b]4;1;rgb:ff/00/00\x07",
+                b"\x1b[?62;22c\x1b[0n\x1b[?997;2n\x1b[?2031;2$y",
+            ],
+        );
+        assert_eq!(
+            parsed,
+            vec![
+                Parsed::Input(b"ab".to_vec()),
+                Parsed::Reply(Reply::Background(Rgb {
+                    r: 0x1e1e,
+                    g: 0x1e1e,
+                    b: 0x2e2e
+                })),
+                Parsed::Input(b"c".to_vec()),
+                Parsed::Reply(Reply::Palette {
+                    index: 1,
+                    rgb: Rgb {
+                        r: 0xff00,
+                        g: 0,
+                        b: 0
+                    }
+                }),
+                Parsed::Reply(Reply::DeviceAttributes(vec![62, 22])),
+                Parsed::Reply(Reply::Status(0)),
+                Parsed::Reply(Reply::ColorScheme(Theme::Light)),
+                Parsed::Reply(Reply::ColorSchemeUpdates(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reply_parser_pass_through() {
+        let mut parser = ReplyParser::new();
+        // Arrow key, ALT + x, mouse report and unknown OSC
+        let input: &[u8] = b"\x1b[A\x1bx\x1b[<0;1;2M\x1b]52;c;YQ==\x07\x1b\x1b[B";
+        assert_eq!(parser.feed(input), vec![Parsed::Input(input.to_vec())]);
+
+        // A lone ESC is held until flushed
+        assert_eq!(parser.feed(b"q\x1b"), vec![Parsed::Input(b"q".to_vec())]);
+        assert_eq!(parser.flush(), b"\x1b");
+        assert_eq!(parser.flush(), b"");
+
+        // Malformed color is passed through
+        let input: &[u8] = b"\x1b]11;rgb:12345/0/0\x07";
+        assert_eq!(parser.feed(input), vec![Parsed::Input(input.to_vec())]);
+
+        // Broken sequence is passed through up to the next reply
+        assert_eq!(
+            parser.feed(b"\x1b]11;\x1b[0n"),
+            vec![
+                Parsed::Input(b"\x1b]11;".to_vec()),
+                Parsed::Reply(Reply::Status(0))
+            ]
+        );
+    }
+}
@@ -0,0 +1,406 @@
+use crate::{clear_stdin, non_terminal_stream, pop_key_event, Error, EventReader, Stream};
+use crossterm::event::Event;
+use crossterm::terminal;
+use log::debug;
+use scopeguard::defer;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::io::{self, Write};
+use std::thread::sleep;
+use std::time::Duration;
+
+/// I/O to the terminal used by queries
+///
+/// `EventReader` only abstracts reading. This bundles everything a query needs, so the
+/// queries can be run on another device or in memory.
+pub trait TerminalIo {
+    /// Write `buf` to the terminal and flush it.
+    fn write_all(&self, buf: &[u8]) -> io::Result<()>;
+    /// Read a terminal event, or get `Ok(None)` if nothing arrives within `timeout`.
+    fn read_event(&self, timeout: Duration) -> Result<Option<Event>, Error>;
+    /// Check whether the terminal is in raw mode.
+    fn is_raw_mode_enabled(&self) -> Result<bool, Error>;
+    /// Enable raw mode, which is required to read replies without waiting for a newline.
+    fn enable_raw_mode(&self) -> Result<(), Error>;
+    /// Disable raw mode enabled by `enable_raw_mode()`.
+    fn disable_raw_mode(&self) -> Result<(), Error>;
+    /// Check whether the device is a terminal, so that queries can be answered.
+    fn is_terminal(&self) -> bool;
+    /// Get the stream which is not a terminal, reported by `Error::NotATerminal`.
+    ///
+    /// The default is `Stream::TerminalIo` unless `is_terminal()` is true.
+    fn non_terminal_stream(&self) -> Option<Stream> {
+        if self.is_terminal() {
+            None
+        } else {
+            Some(Stream::TerminalIo)
+        }
+    }
+}
+
+/// Terminal I/O through stdio, with raw mode toggled by `crossterm`
+///
+/// Queries are written to stderr, and replies are read from stdin by `event_reader`.
+/// stdout is not used, so it may be redirected.
+/// `rgb()` reads them by `CrosstermEventReader`.
+#[derive(Debug, Default)]
+pub struct StdioIo<R> {
+    event_reader: R,
+}
+
+impl<R: EventReader> StdioIo<R> {
+    pub fn new(event_reader: R) -> Self {
+        StdioIo { event_reader }
+    }
+}
+
+impl<R: EventReader> TerminalIo for StdioIo<R> {
+    fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        let mut stderr = io::stderr();
+        stderr.write_all(buf)?;
+        stderr.flush()
+    }
+
+    fn read_event(&self, timeout: Duration) -> Result<Option<Event>, Error> {
+        if self.event_reader.poll(timeout)? {
+            Ok(Some(self.event_reader.read_event()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn is_raw_mode_enabled(&self) -> Result<bool, Error> {
+        Ok(terminal::is_raw_mode_enabled()?)
+    }
+
+    fn enable_raw_mode(&self) -> Result<(), Error> {
+        terminal::enable_raw_mode().map_err(|source| Error::RawModeFailed { source })
+    }
+
+    fn disable_raw_mode(&self) -> Result<(), Error> {
+        Ok(terminal::disable_raw_mode()?)
+    }
+
+    fn is_terminal(&self) -> bool {
+        non_terminal_stream().is_none()
+    }
+
+    fn non_terminal_stream(&self) -> Option<Stream> {
+        non_terminal_stream()
+    }
+}
+
+/// Terminal I/O through `/dev/tty`
+///
+/// This works even if stdio is redirected, as long as the process has a controlling terminal.
+/// Raw mode is toggled by termios of `/dev/tty`.
+#[cfg(not(target_os = "windows"))]
+pub struct TtyIo {
+    file: std::fs::File,
+    event_reader: crate::TtyEventReader,
+    original: Cell<Option<libc::termios>>,
+}
+
+#[cfg(not(target_os = "windows"))]
+impl TtyIo {
+    /// Open `/dev/tty`, the controlling terminal of the process.
+    pub fn open() -> Result<Self, Error> {
+        use std::os::unix::io::AsRawFd;
+
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/tty")?;
+        let event_reader = crate::TtyEventReader::with_fd(file.as_raw_fd());
+        Ok(TtyIo {
+            file,
+            event_reader,
+            original: Cell::new(None),
+        })
+    }
+
+    fn termios(&self) -> Result<libc::termios, Error> {
+        use std::os::unix::io::AsRawFd;
+
+        let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+        if unsafe { libc::tcgetattr(self.file.as_raw_fd(), &mut termios) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(termios)
+    }
+
+    fn set_termios(&self, termios: &libc::termios) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        if unsafe { libc::tcsetattr(self.file.as_raw_fd(), libc::TCSANOW, termios) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+// `libc::termios` implements `Debug` only with the `extra_traits` feature of libc
+#[cfg(not(target_os = "windows"))]
+impl Debug for TtyIo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TtyIo")
+            .field("file", &self.file)
+            .field("event_reader", &self.event_reader)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+impl TerminalIo for TtyIo {
+    fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        let mut file = &self.file;
+        file.write_all(buf)?;
+        file.flush()
+    }
+
+    fn read_event(&self, timeout: Duration) -> Result<Option<Event>, Error> {
+        if self.event_reader.poll(timeout)? {
+            Ok(Some(self.event_reader.read_event()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn is_raw_mode_enabled(&self) -> Result<bool, Error> {
+        Ok(self.termios()?.c_lflag & libc::ICANON == 0)
+    }
+
+    fn enable_raw_mode(&self) -> Result<(), Error> {
+        let original = self.termios()?;
+        let mut termios = original;
+        unsafe { libc::cfmakeraw(&mut termios) };
+        self.set_termios(&termios)
+            .map_err(|source| Error::RawModeFailed { source })?;
+        self.original.set(Some(original));
+        Ok(())
+    }
+
+    fn disable_raw_mode(&self) -> Result<(), Error> {
+        match self.original.take() {
+            Some(original) => Ok(self.set_termios(&original)?),
+            None => {
+                let mut termios = self.termios()?;
+                termios.c_lflag |= libc::ICANON | libc::ECHO | libc::ISIG | libc::IEXTEN;
+                Ok(self.set_termios(&termios)?)
+            }
+        }
+    }
+
+    fn is_terminal(&self) -> bool {
+        use std::io::IsTerminal;
+
+        self.file.is_terminal()
+    }
+}
+
+/// Terminal I/O in memory, which replies `input` and records the queries
+///
+/// This is useful to test an application using termbg without a terminal.
+#[derive(Debug, Default)]
+pub struct MemoryIo {
+    input: RefCell<VecDeque<u8>>,
+    output: RefCell<Vec<u8>>,
+    raw: Cell<bool>,
+}
+
+impl MemoryIo {
+    pub fn new(input: &[u8]) -> Self {
+        MemoryIo {
+            input: RefCell::new(input.iter().copied().collect()),
+            ..Default::default()
+        }
+    }
+
+    /// Bytes written so far
+    pub fn output(&self) -> Vec<u8> {
+        self.output.borrow().clone()
+    }
+}
+
+impl TerminalIo for MemoryIo {
+    fn write_all(&self, buf: &[u8]) -> io::Result<()> {
+        self.output.borrow_mut().extend_from_slice(buf);
+        Ok(())
+    }
+
+    fn read_event(&self, timeout: Duration) -> Result<Option<Event>, Error> {
+        let event = pop_key_event(&mut self.input.borrow_mut());
+        if event.is_none() {
+            // Nothing more arrives
+            sleep(timeout);
+        }
+        Ok(event)
+    }
+
+    fn is_raw_mode_enabled(&self) -> Result<bool, Error> {
+        Ok(self.raw.get())
+    }
+
+    fn enable_raw_mode(&self) -> Result<(), Error> {
+        self.raw.set(true);
+        Ok(())
+    }
+
+    fn disable_raw_mode(&self) -> Result<(), Error> {
+        self.raw.set(false);
+        Ok(())
+    }
+
+    fn is_terminal(&self) -> bool {
+        true
+    }
+}
+
+/// `EventReader` over `TerminalIo`
+#[derive(Debug)]
+pub(crate) struct IoEventReader<'a, T> {
+    io: &'a T,
+    pending: RefCell<Option<Event>>,
+}
+
+impl<T: TerminalIo> EventReader for IoEventReader<'_, T> {
+    fn read_event(&self) -> Result<Event, Error> {
+        if let Some(event) = self.pending.borrow_mut().take() {
+            return Ok(event);
+        }
+        loop {
+            if let Some(event) = self.io.read_event(Duration::from_millis(100))? {
+                return Ok(event);
+            }
+        }
+    }
+
+    fn poll(&self, timeout: Duration) -> Result<bool, Error> {
+        if self.pending.borrow().is_some() {
+            return Ok(true);
+        }
+        let event = self.io.read_event(timeout)?;
+        let ret = event.is_some();
+        *self.pending.borrow_mut() = event;
+        Ok(ret)
+    }
+}
+
+/// `Write` over `TerminalIo`
+#[derive(Debug)]
+pub(crate) struct IoWriter<'a, T>(&'a T);
+
+impl<T: TerminalIo> Write for IoWriter<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Run `f` with `io` in raw mode, then restore the previous raw mode status and discard any
+/// excess input.
+pub(crate) fn with_terminal_io<T, U, F>(io: &T, f: F) -> Result<U, Error>
+where
+    T: TerminalIo + Debug,
+    F: FnOnce(&IoEventReader<'_, T>, &mut IoWriter<'_, T>) -> Result<U, Error>,
+{
+    // Not a terminal, so don't try to query.
+    if let Some(stream) = io.non_terminal_stream() {
+        return Err(Error::NotATerminal { stream });
+    }
+
+    let raw_before = io.is_raw_mode_enabled()?;
+    let event_reader = IoEventReader {
+        io,
+        pending: RefCell::new(None),
+    };
+
+    defer! {
+        match io.is_raw_mode_enabled() {
+            Ok(is_raw) if is_raw == raw_before => {
+                debug!("Raw mode status unchanged from raw={raw_before}.\r");
+            }
+            Ok(_) => {
+                let ret = if raw_before {
+                    io.enable_raw_mode()
+                } else {
+                    io.disable_raw_mode()
+                };
+                if let Err(e) = ret {
+                    debug!("Failed to restore raw mode: {e:?} to raw={raw_before}\r");
+                } else {
+                    debug!("Raw mode restored to previous state (raw={raw_before}).\r");
+                }
+            }
+            Err(e) => debug!("Failed to check raw mode status: {e:?}\r"),
+        }
+
+        if let Err(e) = clear_stdin(&event_reader) {
+            debug!("Failed to clear stdin: {e:?}\r");
+        } else {
+            debug!("Cleared any excess from stdin.\r");
+        }
+    }
+
+    if !raw_before {
+        io.enable_raw_mode()?;
+    }
+
+    f(&event_reader, &mut IoWriter(io))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{detect_io_of, from_xterm_through, xterm_latency, Rgb, Terminal};
+
+    #[test]
+    fn test_memory_io() {
+        let io = MemoryIo::new(b"\x1b]11;rgb:1e1e/1e1e/2e2e\x1b\\");
+        let rgb = from_xterm_through(&io, &[], Duration::from_millis(100));
+        assert_eq!(
+            rgb.unwrap(),
+            Rgb {
+                r: 0x1e1e,
+                g: 0x1e1e,
+                b: 0x2e2e
+            }
+        );
+        assert_eq!(io.output(), b"\x1b]11;?\x1b\\");
+        assert!(!io.is_raw_mode_enabled().unwrap());
+
+        // Raw mode enabled by the caller is kept
+        let io = MemoryIo::new(b"\x1b[0n");
+        io.enable_raw_mode().unwrap();
+        assert!(xterm_latency(&io, &[], Duration::from_millis(100)).is_ok());
+        assert_eq!(io.output(), b"\x1b[5n");
+        assert!(io.is_raw_mode_enabled().unwrap());
+
+        // The timeout shorter than the polling interval is respected
+        let io = MemoryIo::new(b"");
+        let start_time = std::time::Instant::now();
+        assert!(matches!(
+            xterm_latency(&io, &[], Duration::from_millis(10)),
+            Err(Error::Timeout { .. })
+        ));
+        assert!(start_time.elapsed() < Duration::from_millis(60));
+    }
+
+    #[test]
+    fn test_detect_io_exhausted_timeout() {
+        // The background color query is not sent after the color scheme query used up the
+        // timeout
+        let io = MemoryIo::new(b"");
+        let timeout = Duration::from_millis(10);
+        match detect_io_of(&io, Terminal::XtermCompatible, timeout) {
+            Err(Error::Timeout { elapsed, .. }) => assert!(elapsed >= timeout),
+            ret => panic!("unexpected result: {:?}", ret),
+        }
+        assert_eq!(io.output(), b"\x1b[?996n\x1b[c");
+    }
+}
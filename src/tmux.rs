@@ -0,0 +1,194 @@
+use crate::{
+    from_xterm_through, parse_hex_color, CrosstermEventReader, Error, Rgb, Source, StdioIo,
+};
+use log::debug;
+use std::process::Command;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// Format of `display-message` to get `allow-passthrough`
+pub(crate) const PASSTHROUGH_FORMAT: &str = "#{allow-passthrough}";
+
+/// Check whether tmux passes queries through to the outer terminal.
+///
+/// tmux 3.3 or later requires `allow-passthrough`. Older tmux doesn't know the option,
+/// which is expanded to an empty string, and always passes queries through.
+pub(crate) fn allows_passthrough() -> bool {
+    static ALLOWS_PASSTHROUGH: OnceLock<bool> = OnceLock::new();
+    *ALLOWS_PASSTHROUGH.get_or_init(|| {
+        let output = Command::new("tmux")
+            .args(["display-message", "-p", PASSTHROUGH_FORMAT])
+            .output();
+        match output {
+            Ok(output) if output.status.success() => {
+                let value = String::from_utf8_lossy(&output.stdout);
+                debug!("tmux allow-passthrough={value:?}\r");
+                value.trim() != "off"
+            }
+            _ => true,
+        }
+    })
+}
+
+/// Ask tmux itself for the background color.
+///
+/// tmux 3.3 or later answers OSC 11 by itself from the colors of the pane, which are
+/// inherited from the outer terminal. Older tmux doesn't answer it, so `window-style`
+/// configured in tmux is used instead.
+pub(crate) fn from_tmux(timeout: Duration) -> Result<(Rgb, Source), Error> {
+    let rgb = from_xterm_through(&StdioIo::new(CrosstermEventReader), &[], timeout);
+    debug!("rgb answered by tmux={rgb:?}\r");
+    rgb.map(|rgb| (rgb, Source::Tmux)).or_else(|e| {
+        from_tmux_style()
+            .map(|rgb| (rgb, Source::TmuxStyle))
+            .map_err(|_| e)
+    })
+}
+
+/// Get the background color from `window-active-style` or `window-style`.
+pub(crate) fn from_tmux_style() -> Result<Rgb, Error> {
+    let output = Command::new("tmux")
+        .args([
+            "display-message",
+            "-p",
+            "#{window-active-style};#{window-style}",
+        ])
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::Unsupported);
+    }
+    let styles = String::from_utf8_lossy(&output.stdout);
+    debug!("tmux styles={styles}\r");
+
+    styles
+        .trim()
+        .split(';')
+        .find_map(parse_style_bg)
+        .ok_or(Error::Unsupported)
+}
+
+/// Parse the background color of tmux style like `fg=white,bg=#1e1e2e`.
+///
+/// `default` and `terminal` refer to the outer terminal, so they are not a color.
+fn parse_style_bg(style: &str) -> Option<Rgb> {
+    let bg = style
+        .split([',', ' '])
+        .find_map(|attr| attr.trim().strip_prefix("bg="))?;
+    parse_color(bg)
+}
+
+fn parse_color(color: &str) -> Option<Rgb> {
+    const NAMES: [&str; 8] = [
+        "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+    ];
+
+    if color.starts_with('#') {
+        return parse_hex_color(color);
+    }
+    if let Some(index) = color
+        .strip_prefix("colour")
+        .or_else(|| color.strip_prefix("color"))
+    {
+        return index.parse().ok().map(ansi256_to_rgb);
+    }
+    if let Some(name) = color.strip_prefix("bright") {
+        let index = NAMES.iter().position(|x| *x == name)?;
+        return Some(ansi256_to_rgb(index as u8 + 8));
+    }
+    let index = NAMES.iter().position(|x| *x == color)?;
+    Some(ansi256_to_rgb(index as u8))
+}
+
+/// Default color of the xterm 256 color palette.
+fn ansi256_to_rgb(index: u8) -> Rgb {
+    // xterm default color table
+    const BASE: [(u16, u16, u16); 16] = [
+        (0, 0, 0),
+        (205, 0, 0),
+        (0, 205, 0),
+        (205, 205, 0),
+        (0, 0, 238),
+        (205, 0, 205),
+        (0, 205, 205),
+        (229, 229, 229),
+        (127, 127, 127),
+        (255, 0, 0),
+        (0, 255, 0),
+        (255, 255, 0),
+        (92, 92, 255),
+        (255, 0, 255),
+        (0, 255, 255),
+        (255, 255, 255),
+    ];
+
+    let (r, g, b) = match index {
+        0..=15 => BASE[index as usize],
+        16..=231 => {
+            let level = |x: u8| if x == 0 { 0 } else { x as u16 * 40 + 55 };
+            let i = index - 16;
+            (level(i / 36), level(i / 6 % 6), level(i % 6))
+        }
+        232..=255 => {
+            let level = (index - 232) as u16 * 10 + 8;
+            (level, level, level)
+        }
+    };
+
+    Rgb {
+        r: r * 257,
+        g: g * 257,
+        b: b * 257,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ansi256_to_rgb() {
+        assert_eq!(ansi256_to_rgb(0), Rgb { r: 0, g: 0, b: 0 });
+        assert_eq!(
+            ansi256_to_rgb(15),
+            Rgb {
+                r: 0xffff,
+                g: 0xffff,
+                b: 0xffff
+            }
+        );
+        assert_eq!(
+            ansi256_to_rgb(196),
+            Rgb {
+                r: 0xffff,
+                g: 0,
+                b: 0
+            }
+        );
+        assert_eq!(
+            ansi256_to_rgb(232),
+            Rgb {
+                r: 0x0808,
+                g: 0x0808,
+                b: 0x0808
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_style_bg() {
+        assert_eq!(
+            parse_style_bg("fg=white,bg=#1e1e2e"),
+            Some(Rgb {
+                r: 0x1e1e,
+                g: 0x1e1e,
+                b: 0x2e2e
+            })
+        );
+        assert_eq!(parse_style_bg("bg=colour16"), Some(ansi256_to_rgb(16)));
+        assert_eq!(parse_style_bg("bg=brightwhite"), Some(ansi256_to_rgb(15)));
+        assert_eq!(parse_style_bg("fg=black bg=black"), Some(ansi256_to_rgb(0)));
+        assert_eq!(parse_style_bg("bg=default"), None);
+        assert_eq!(parse_style_bg("default"), None);
+        assert_eq!(parse_style_bg(""), None);
+    }
+}
@@ -0,0 +1,89 @@
+use crate::{theme, Theme};
+use log::debug;
+use ratatui::style::{Color, Modifier, Style};
+use std::time::Duration;
+
+/// Styles of a ratatui application for the detected theme
+///
+/// Colors are chosen from the 16 ANSI colors, so they follow the palette of the terminal.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TuiStyles {
+    /// Theme which the styles are for
+    pub theme: Theme,
+    /// Normal text
+    pub base: Style,
+    /// Less important text
+    pub muted: Style,
+    /// Emphasized text
+    pub accent: Style,
+    /// Selected item
+    pub highlight: Style,
+    /// Borders of blocks
+    pub border: Style,
+    /// Error messages
+    pub error: Style,
+}
+
+impl TuiStyles {
+    /// Styles for light background
+    pub const LIGHT: TuiStyles = TuiStyles {
+        theme: Theme::Light,
+        base: Style::new(),
+        muted: Style::new().fg(Color::DarkGray),
+        accent: Style::new().fg(Color::Blue),
+        highlight: Style::new().fg(Color::Black).bg(Color::Gray),
+        border: Style::new().fg(Color::Gray),
+        error: Style::new().fg(Color::Red).add_modifier(Modifier::BOLD),
+    };
+
+    /// Styles for dark background
+    pub const DARK: TuiStyles = TuiStyles {
+        theme: Theme::Dark,
+        base: Style::new(),
+        muted: Style::new().fg(Color::Gray),
+        accent: Style::new().fg(Color::LightBlue),
+        highlight: Style::new().fg(Color::White).bg(Color::DarkGray),
+        border: Style::new().fg(Color::DarkGray),
+        error: Style::new()
+            .fg(Color::LightRed)
+            .add_modifier(Modifier::BOLD),
+    };
+
+    pub fn from_theme(theme: Theme) -> Self {
+        match theme {
+            Theme::Light => TuiStyles::LIGHT,
+            Theme::Dark => TuiStyles::DARK,
+        }
+    }
+
+    /// Detect the theme and get the styles for it
+    ///
+    /// This can be called before or after `Terminal::new`, and in the alternate screen.
+    /// If raw mode is already enabled, it is kept enabled.
+    /// Call this before starting to read events, otherwise the reply may be consumed by the
+    /// event loop of the application. In a running event loop, use `theme_with()` and
+    /// `from_theme()` instead.
+    ///
+    /// If detection fails, the styles for dark background are returned.
+    pub fn detect(timeout: Duration) -> Self {
+        match theme(timeout) {
+            Ok(theme) => TuiStyles::from_theme(theme),
+            Err(e) => {
+                debug!("theme detection failed: {e:?}\r");
+                TuiStyles::DARK
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_theme() {
+        assert_eq!(TuiStyles::from_theme(Theme::Light).theme, Theme::Light);
+        assert_eq!(TuiStyles::from_theme(Theme::Dark).theme, Theme::Dark);
+        assert_ne!(TuiStyles::LIGHT.accent, TuiStyles::DARK.accent);
+    }
+}
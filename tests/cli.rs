@@ -0,0 +1,125 @@
+#![cfg(all(feature = "cli", not(target_os = "windows")))]
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::process::{Command, Output, Stdio};
+use std::thread;
+
+/// Replies of the fake terminal for each query
+const REPLIES: &[(&[u8], &[u8])] = &[
+    (b"\x1b[c", b"\x1b[?62;22c"),
+    (b"\x1b[>c", b"\x1b[>41;390;0c"),
+    (b"\x1b[>q", b"\x1bP>|FakeTerm 1.0\x1b\\"),
+    (b"\x1b[5n", b"\x1b[0n"),
+    (b"\x1b]10;?", b"\x1b]10;rgb:0000/0000/0000\x1b\\"),
+    (b"\x1b]11;?", b"\x1b]11;rgb:ffff/ffff/ffff\x1b\\"),
+    (b"\x1b]4;1;?", b"\x1b]4;1;rgb:cdcd/0000/0000\x1b\\"),
+];
+
+/// Run `termbg` on a pseudo terminal answering `REPLIES`, with stdout captured.
+fn run_termbg(args: &[&str]) -> Output {
+    let (mut master, slave) = unsafe {
+        let mut master = 0;
+        let mut slave = 0;
+        let ret = libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            std::ptr::null(),
+        );
+        assert_eq!(ret, 0, "openpty failed");
+        (File::from_raw_fd(master), File::from_raw_fd(slave))
+    };
+
+    let mut writer = master.try_clone().unwrap();
+    let terminal = thread::spawn(move || {
+        let mut pending = Vec::new();
+        let mut buf = [0; 256];
+        // Reading fails after the child exits and the slave is closed
+        while let Ok(len @ 1..) = master.read(&mut buf) {
+            pending.extend_from_slice(&buf[..len]);
+            while let Some((pos, query, reply)) = REPLIES
+                .iter()
+                .filter_map(|(query, reply)| {
+                    let pos = pending.windows(query.len()).position(|x| x == *query)?;
+                    Some((pos, query, reply))
+                })
+                .min_by_key(|(pos, _, _)| *pos)
+            {
+                writer.write_all(reply).unwrap();
+                pending.drain(..pos + query.len());
+            }
+        }
+    });
+
+    let child = Command::new(env!("CARGO_BIN_EXE_termbg"))
+        .args(args)
+        .env_clear()
+        .env("TERM", "xterm-256color")
+        .stdin(slave.try_clone().unwrap())
+        .stderr(slave)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    terminal.join().unwrap();
+    output
+}
+
+#[test]
+fn test_captured_stdout() {
+    let output = run_termbg(&["--format", "env", "theme"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("TERMBG_THEME=light"), "{}", stdout);
+    assert_eq!(output.status.code(), Some(10));
+}
+
+#[test]
+fn test_query_subcommands() {
+    let output = run_termbg(&["--format", "json", "bg"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("\"bg\":\"#ffffff\""), "{}", stdout);
+    assert_eq!(output.status.code(), Some(0));
+
+    let output = run_termbg(&["fg"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "#000000\n");
+
+    let output = run_termbg(&["palette", "1"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "1 #cd0000\n");
+
+    let output = run_termbg(&["--format", "env", "latency", "--samples", "2"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("TERMBG_LATENCY_MAX_MS="), "{}", stdout);
+    assert_eq!(output.status.code(), Some(0));
+
+    let output = run_termbg(&["terminal"]);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "terminal xterm_compatible\nname FakeTerm\nversion 1.0\n"
+    );
+}
+
+#[test]
+fn test_diagnose_without_terminal() {
+    let output = Command::new(env!("CARGO_BIN_EXE_termbg"))
+        .args(["--format", "json", "diagnose"])
+        .env_clear()
+        .env("TERM", "xterm-256color")
+        .env("COLORFGBG", "0;15")
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // Each query fails by itself, and the fallback still runs
+    assert!(!stdout.contains("\"preflight\""), "{}", stdout);
+    assert!(
+        stdout.contains("\"error\":\"stdin is not a terminal\",\"query\":\"\",\"response\":\"\",\"source\":\"osc11\""),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("\"source\":\"colorfgbg\""), "{}", stdout);
+    assert!(stdout.contains("\"theme\":\"light\""), "{}", stdout);
+}